@@ -0,0 +1,1875 @@
+mod data;
+mod expr;
+mod files;
+mod flow;
+mod instruction;
+mod labels;
+mod lexer;
+mod listing;
+mod macros;
+mod parser;
+mod peephole;
+mod placement;
+mod sections;
+mod source;
+
+use crate::object::Object;
+use expr::{Expr, ExprError};
+use instruction::{encode, lookup, Arg};
+use lexer::Token;
+use parser::{Operand, OperandKind, Statement, StatementKind};
+use placement::Block;
+use sections::SectionState;
+use source::{Line, Loader, SourceFile};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub use files::{Disk, FileSystem, Sources};
+pub use source::{Location, Origin};
+
+const ADDRESS_SPACE: u32 = 0x10000;
+// Frames printed at each end of a long backtrace, like the one of a macro that
+// expands too deeply.
+const BACKTRACE_ENDS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub location: Location,
+    pub backtrace: Vec<Origin>,
+    pub message: String,
+    // The source line the location points into, when it is known.
+    pub excerpt: Option<String>,
+}
+
+impl Diagnostic {
+    // The message followed by the source line with a caret under the offending
+    // token, as printed by the command line.
+    pub fn report(&self) -> String {
+        let mut out = self.headline();
+        if let Some(text) = &self.excerpt {
+            let number = self.location.line.to_string();
+            let before: String = text
+                .chars()
+                .take(self.location.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let token = text
+                .chars()
+                .skip(before.chars().count())
+                .take_while(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '@'))
+                .count();
+            out += &format!("\n {} | {}", number, text);
+            out += &format!(
+                "\n {} | {}{}",
+                " ".repeat(number.len()),
+                before,
+                "^".repeat(token.max(1))
+            );
+        }
+        for frame in self.frames() {
+            out += &format!("\n    {}", frame);
+        }
+        out
+    }
+
+    fn frames(&self) -> Vec<String> {
+        let mut frames: Vec<String> = self.backtrace.iter().map(Origin::to_string).collect();
+        let len = frames.len();
+        if len > 2 * BACKTRACE_ENDS + 1 {
+            frames.splice(
+                BACKTRACE_ENDS..len - BACKTRACE_ENDS,
+                [format!("... {} more", len - 2 * BACKTRACE_ENDS)],
+            );
+        }
+        frames
+    }
+
+    fn headline(&self) -> String {
+        match self.severity {
+            Severity::Error => format!("{}: {}", self.location, self.message),
+            Severity::Warning => format!("{}: warning: {}", self.location, self.message),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.headline())?;
+        for frame in self.frames() {
+            write!(f, "\n    {}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+// Address and bytes produced by one source line in the final pass.
+struct Record {
+    address: u32,
+    bytes: Vec<u8>,
+}
+
+// A `.const` whose value depends on symbols defined later in the source.
+struct PendingConst {
+    name: String,
+    expr: Expr,
+    pc: u32,
+    section: Option<usize>,
+    line: usize,
+    column: usize,
+}
+
+// Options for `assemble`, matching the `-I` and `-D` flags of `cupana asm`.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub include_dirs: Vec<PathBuf>,
+    // `NAME` or `NAME=value`, as given to `Assembler::add_define`.
+    pub defines: Vec<String>,
+    // Runs the peephole optimizer, like `-O`.
+    pub optimize: bool,
+}
+
+// An assembled program: the ROM image, the final value of every symbol and the
+// warnings that did not stop the assembly.
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub rom: Vec<u8>,
+    pub symbols: BTreeMap<String, i64>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+// Assembles the first file of `sources` without touching the disk: `.include`
+// and `.incbin` are looked up among the other files.
+pub fn assemble(sources: &Sources, options: &Options) -> Result<Image, Vec<Diagnostic>> {
+    let Some(main) = sources.main() else {
+        return Err(vec![SourceFile::root("<sources>", None).error(
+            0,
+            0,
+            "no source file given",
+        )]);
+    };
+    let main = main.to_path_buf();
+    let mut assembler = Assembler::new();
+    assembler.set_files(sources.clone());
+    assembler.include_dirs.clone_from(&options.include_dirs);
+    assembler.optimize = options.optimize;
+    let mut errors = Vec::new();
+    for definition in &options.defines {
+        if let Err(message) = assembler.add_define(definition) {
+            let name = format!("-D {}", definition);
+            errors.push(SourceFile::root(&name, None).error(0, 0, message));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let rom = assembler.assemble_file(&main)?;
+    Ok(Image {
+        rom,
+        symbols: assembler.symbols.into_iter().collect(),
+        warnings: assembler.warnings,
+    })
+}
+
+pub struct Assembler {
+    include_dirs: Vec<PathBuf>,
+    files: Box<dyn FileSystem>,
+    defines: HashMap<String, i64>,
+    optimize: bool,
+    symbols: HashMap<String, i64>,
+    pending: Vec<PendingConst>,
+    pc: u32,
+    statement_pc: u32,
+    image: Vec<u8>,
+    emit: bool,
+    lines: Vec<Line>,
+    records: Vec<Record>,
+    blocks: Vec<Block>,
+    errors: Vec<Diagnostic>,
+    warnings: Vec<Diagnostic>,
+    // Lines whose errors were already reported in the first pass.
+    failed: HashSet<usize>,
+    // Object output only: the section being assembled and where each label lives.
+    object: bool,
+    sections: Vec<SectionState>,
+    section: Option<usize>,
+    symbol_sections: HashMap<String, usize>,
+    exports: Vec<(String, usize, usize)>,
+    imports: Vec<String>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler {
+            include_dirs: Vec::new(),
+            files: Box::new(Disk),
+            defines: HashMap::new(),
+            optimize: false,
+            symbols: HashMap::new(),
+            pending: Vec::new(),
+            pc: 0,
+            statement_pc: 0,
+            image: Vec::new(),
+            emit: false,
+            lines: Vec::new(),
+            records: Vec::new(),
+            blocks: Vec::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            failed: HashSet::new(),
+            object: false,
+            sections: Vec::new(),
+            section: None,
+            symbol_sections: HashMap::new(),
+            exports: Vec::new(),
+            imports: Vec::new(),
+        }
+    }
+
+    // Where source and `.incbin` files are read from; the disk by default.
+    pub fn set_files(&mut self, files: impl FileSystem + 'static) {
+        self.files = Box::new(files);
+    }
+
+    // Shortens instructions where the result is provably the same; see peephole.rs.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    pub fn add_include_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.include_dirs.push(dir.into());
+    }
+
+    // Predefines a symbol, as `NAME` (with value 1) or `NAME=value`.
+    pub fn add_define(&mut self, definition: &str) -> Result<(), String> {
+        let (name, value) = definition.split_once('=').unwrap_or((definition, "1"));
+        if !macros::is_identifier(name) || parser::register(name).is_some() {
+            return Err(format!("invalid symbol name '{}'", name));
+        }
+        let tokens = lexer::tokenize(value).unwrap_or_default();
+        let value = match tokens.iter().map(|s| &s.token).collect::<Vec<_>>()[..] {
+            [Token::Number(value)] => *value,
+            [Token::Minus, Token::Number(value)] => -value,
+            _ => return Err(format!("invalid value '{}' for '{}'", value, name)),
+        };
+        self.defines.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    // Every error found is returned; warnings are kept in `warnings()`.
+    pub fn assemble(&mut self, source: &str) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        let lines = self.load(source)?;
+        self.assemble_lines(lines, false)?;
+        Ok(std::mem::take(&mut self.image))
+    }
+
+    pub fn assemble_file(&mut self, path: &Path) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        let lines = self.load_file(path)?;
+        self.assemble_lines(lines, false)?;
+        Ok(std::mem::take(&mut self.image))
+    }
+
+    // Assembles into a relocatable object, to be combined with others by the linker.
+    pub fn assemble_object(&mut self, source: &str) -> Result<Object, Vec<Diagnostic>> {
+        let lines = self.load(source)?;
+        self.assemble_lines(lines, true)?;
+        self.build_object()
+    }
+
+    pub fn assemble_object_file(&mut self, path: &Path) -> Result<Object, Vec<Diagnostic>> {
+        let lines = self.load_file(path)?;
+        self.assemble_lines(lines, true)?;
+        self.build_object()
+    }
+
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    fn load(&mut self, source: &str) -> Result<Vec<Line>, Vec<Diagnostic>> {
+        self.warnings.clear();
+        Loader::new(&self.include_dirs, &*self.files, &self.defines)
+            .load(SourceFile::root("<source>", None), source)
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<Vec<Line>, Vec<Diagnostic>> {
+        self.warnings.clear();
+        let name = path.display().to_string();
+        let root = SourceFile::root(&name, Some(path.to_path_buf()));
+        let source = self
+            .files
+            .read_to_string(path)
+            .map_err(|err| vec![root.error(0, 0, format!("cannot read '{}': {}", name, err))])?;
+        Loader::new(&self.include_dirs, &*self.files, &self.defines).load(root, &source)
+    }
+
+    fn assemble_lines(
+        &mut self,
+        mut lines: Vec<Line>,
+        object: bool,
+    ) -> Result<(), Vec<Diagnostic>> {
+        if self.optimize {
+            peephole::optimize(&mut lines, &self.defines);
+        }
+        self.object = object;
+        self.symbols.clone_from(&self.defines);
+        self.pending.clear();
+        self.image.clear();
+        self.records.clear();
+        self.lines.clear();
+        self.errors.clear();
+        self.failed.clear();
+        self.sections.clear();
+        self.symbol_sections.clear();
+        self.exports.clear();
+        self.imports.clear();
+        self.pass(&lines, false);
+        self.resolve_pending(&lines);
+        self.pass(&lines, true);
+        self.lines = lines;
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+        self.warn_unused();
+        Ok(())
+    }
+
+    // Labels no expression refers to. Constants, exported labels and labels made
+    // by macros are left out.
+    fn warn_unused(&mut self) {
+        let mut used = Vec::new();
+        for line in &self.lines {
+            let operands = match &line.statement.kind {
+                StatementKind::Instruction { operands, .. } => operands,
+                StatementKind::Directive { args, .. } => args,
+                StatementKind::Empty => continue,
+            };
+            for operand in operands {
+                if let OperandKind::Expr(expr) = &operand.kind {
+                    expr.symbols(&mut used);
+                }
+            }
+        }
+        let used: HashSet<&str> = used.into_iter().collect();
+
+        for line in &self.lines {
+            let Some(label) = &line.statement.label else {
+                continue;
+            };
+            let constant = matches!(
+                &line.statement.kind,
+                StatementKind::Directive { name, .. } if name == "const"
+            );
+            if constant
+                || used.contains(label.as_str())
+                || label.starts_with(':')
+                || label.contains('@')
+                || self.exports.iter().any(|(name, ..)| name == label)
+            {
+                continue;
+            }
+            let column = line.text.chars().take_while(|c| c.is_whitespace()).count() + 1;
+            self.warnings
+                .push(line.warning(column, format!("label '{}' is never used", label)));
+        }
+    }
+
+    fn pass(&mut self, lines: &[Line], emit: bool) {
+        self.pc = 0;
+        self.emit = emit;
+        self.section = None;
+        self.blocks.clear();
+        if self.object {
+            for section in &mut self.sections {
+                section.pc = 0;
+            }
+            self.switch_section(".text");
+        }
+
+        for (idx, line) in lines.iter().enumerate() {
+            self.statement_pc = self.pc;
+            if emit {
+                self.records.push(Record {
+                    address: self.pc,
+                    bytes: Vec::new(),
+                });
+            }
+            // Lines that failed in the first pass are not tried again, so each
+            // error is reported once.
+            if emit && self.failed.contains(&idx) {
+                continue;
+            }
+            if let Err(err) = self.statement(idx, line) {
+                self.errors.push(err);
+                self.failed.insert(idx);
+            }
+
+            // Lines without bytes are listed at the address they leave behind, e.g. after `.org`.
+            if let Some(record) = self
+                .records
+                .last_mut()
+                .filter(|r| emit && r.bytes.is_empty())
+            {
+                record.address = self.pc;
+            }
+        }
+
+        if let Some(section) = self.section {
+            self.sections[section].pc = self.pc;
+        }
+    }
+
+    fn statement(&mut self, idx: usize, line: &Line) -> Result<(), Diagnostic> {
+        let statement = &line.statement;
+        match &statement.kind {
+            StatementKind::Directive { name, args } if name == "const" => {
+                if !self.emit {
+                    self.constant(idx, line, args)?;
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if let Some(label) = &statement.label {
+            if !self.emit {
+                self.define(line, label, self.pc as i64)?;
+                if let Some(section) = self.section {
+                    self.symbol_sections.insert(label.clone(), section);
+                }
+            }
+        }
+
+        match &statement.kind {
+            StatementKind::Empty => {}
+            StatementKind::Directive { name, args } if name == "global" => {
+                self.export(idx, line, args)?
+            }
+            StatementKind::Instruction { mnemonic, operands } => {
+                self.instruction(line, mnemonic, operands)?
+            }
+            StatementKind::Directive { name, args } => self.directive(line, name, args)?,
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, line: &Line, name: &str, value: i64) -> Result<(), Diagnostic> {
+        self.check_unique(line, name)?;
+        self.symbols.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn check_unique(&self, line: &Line, name: &str) -> Result<(), Diagnostic> {
+        if self.symbols.contains_key(name)
+            || self.pending.iter().any(|c| c.name == name)
+            || self.imports.iter().any(|import| import == name)
+        {
+            return Err(line.error(1, format!("symbol '{}' is already defined", name)));
+        }
+        Ok(())
+    }
+
+    fn constant(&mut self, idx: usize, line: &Line, args: &[Operand]) -> Result<(), Diagnostic> {
+        let statement = &line.statement;
+        let Some(label) = &statement.label else {
+            return Err(line.error(statement.column, ".const requires a label"));
+        };
+        let arg = single_arg(line, statement, args)?;
+        let OperandKind::Expr(expr) = &arg.kind else {
+            return Err(line.error(arg.column, "expected a numeric value"));
+        };
+        match self.settle(expr, self.statement_pc, self.section) {
+            Ok((value, section)) => {
+                self.define(line, label, value)?;
+                if let Some(section) = section {
+                    self.symbol_sections.insert(label.clone(), section);
+                }
+                Ok(())
+            }
+            Err(ExprError::Undefined(_)) => {
+                self.check_unique(line, label)?;
+                self.pending.push(PendingConst {
+                    name: label.clone(),
+                    expr: expr.clone(),
+                    pc: self.statement_pc,
+                    section: self.section,
+                    line: idx,
+                    column: arg.column,
+                });
+                Ok(())
+            }
+            Err(ExprError::Invalid(message)) => Err(line.error(arg.column, message)),
+        }
+    }
+
+    // Constants that referenced forward labels are settled once every label has an address.
+    // Constants that cannot be resolved are reported and set to 0, so the lines
+    // using them do not add errors of their own.
+    fn resolve_pending(&mut self, lines: &[Line]) {
+        while !self.pending.is_empty() {
+            let mut remaining = Vec::new();
+            let mut progress = false;
+            for constant in std::mem::take(&mut self.pending) {
+                match self.settle(&constant.expr, constant.pc, constant.section) {
+                    Ok((value, section)) => {
+                        if let Some(section) = section {
+                            self.symbol_sections.insert(constant.name.clone(), section);
+                        }
+                        self.symbols.insert(constant.name, value);
+                        progress = true;
+                    }
+                    Err(ExprError::Undefined(_)) => remaining.push(constant),
+                    Err(ExprError::Invalid(message)) => {
+                        self.errors
+                            .push(lines[constant.line].error(constant.column, message));
+                        self.symbols.insert(constant.name, 0);
+                        progress = true;
+                    }
+                }
+            }
+
+            if !progress {
+                let mut circular = true;
+                for constant in &remaining {
+                    let Err(ExprError::Undefined(name)) =
+                        self.settle(&constant.expr, constant.pc, constant.section)
+                    else {
+                        unreachable!()
+                    };
+                    if !remaining.iter().any(|c| c.name == name) {
+                        let message = format!("undefined symbol '{}'", name);
+                        self.errors
+                            .push(lines[constant.line].error(constant.column, message));
+                        circular = false;
+                    }
+                }
+                if circular {
+                    let constant = &remaining[0];
+                    let message = format!("circular definition of constant '{}'", constant.name);
+                    self.errors
+                        .push(lines[constant.line].error(constant.column, message));
+                }
+                for constant in remaining.drain(..) {
+                    self.symbols.insert(constant.name, 0);
+                }
+            }
+            self.pending = remaining;
+        }
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<i64, ExprError> {
+        expr.eval(
+            &|name| self.symbols.get(name).copied(),
+            self.statement_pc as i64,
+        )
+    }
+
+    // Forward references are allowed during the first pass unless `required` is set,
+    // since only the instruction size matters at that point.
+    fn value(&self, line: &Line, operand: &Operand, required: bool) -> Result<i64, Diagnostic> {
+        let OperandKind::Expr(expr) = &operand.kind else {
+            return Err(line.error(operand.column, "expected a numeric value"));
+        };
+        match self.eval(expr) {
+            Ok(value) => Ok(value),
+            Err(ExprError::Undefined(_)) if !self.emit && !required => Ok(0),
+            Err(ExprError::Undefined(name)) if !self.emit => Err(line.error(
+                operand.column,
+                format!("symbol '{}' must be defined before use here", name),
+            )),
+            Err(ExprError::Undefined(name)) => {
+                Err(line.error(operand.column, format!("undefined symbol '{}'", name)))
+            }
+            Err(ExprError::Invalid(message)) => Err(line.error(operand.column, message)),
+        }
+    }
+
+    fn write(&mut self, line: &Line, bytes: &[u8]) -> Result<(), Diagnostic> {
+        let end = self.pc + bytes.len() as u32;
+        if end > ADDRESS_SPACE {
+            return Err(line.error(1, "code exceeds the 64kb address space"));
+        }
+        if self.in_bss() && bytes.iter().any(|&b| b != 0) {
+            return Err(line.error(1, "only zero bytes can be placed in .bss"));
+        }
+        if self.emit && !self.object && !bytes.is_empty() {
+            self.place(line, end)?;
+        }
+        if self.emit {
+            let image = match self.section {
+                Some(section) => &mut self.sections[section].data,
+                None => &mut self.image,
+            };
+            if image.len() < end as usize {
+                image.resize(end as usize, 0);
+            }
+            image[self.pc as usize..end as usize].copy_from_slice(bytes);
+            if let Some(record) = self.records.last_mut() {
+                record.bytes.extend_from_slice(bytes);
+            }
+        }
+        self.pc = end;
+        Ok(())
+    }
+
+    fn instruction(
+        &mut self,
+        line: &Line,
+        mnemonic: &str,
+        operands: &[Operand],
+    ) -> Result<(), Diagnostic> {
+        let Some(mnemonic) = lookup(mnemonic) else {
+            return Err(line.error(
+                line.statement.column,
+                format!("unknown instruction '{}'", mnemonic),
+            ));
+        };
+
+        let mut args = Vec::new();
+        let mut fixup = None;
+        for (idx, operand) in operands.iter().enumerate() {
+            args.push(match &operand.kind {
+                OperandKind::Register(reg) => Arg::Register(*reg),
+                OperandKind::Indirect(reg) => Arg::Indirect(*reg),
+                OperandKind::Expr(_) => {
+                    let (value, relocation) = self.field(line, operand)?;
+                    // The literal is always encoded last, so only it can be relocated.
+                    if let Some(relocation) = relocation {
+                        if idx + 1 != operands.len() {
+                            return Err(
+                                line.error(operand.column, "expression cannot be relocated")
+                            );
+                        }
+                        fixup = Some((operand.column, relocation));
+                    }
+                    Arg::Literal(value)
+                }
+                OperandKind::Str(_) => {
+                    return Err(line.error(operand.column, "unexpected string operand"))
+                }
+            });
+        }
+
+        // B forms keep only the low byte of a wider literal, which is usually a mistake.
+        if let (true, Some(Arg::Literal(value))) = (mnemonic.byte, args.last_mut()) {
+            if !(-0x80..=0xFF).contains(value) && (-0x8000..=0xFFFF).contains(value) {
+                if self.emit {
+                    let column = operands[operands.len() - 1].column;
+                    self.warnings.push(line.warning(
+                        column,
+                        format!(
+                            "literal 0x{:04X} truncated to 8 bits (0x{:02X})",
+                            *value as u16, *value as u8
+                        ),
+                    ));
+                }
+                *value &= 0xFF;
+            }
+        }
+
+        let bytes = encode(mnemonic, &args).map_err(|err| {
+            let column = err
+                .operand
+                .map_or(line.statement.column, |idx| operands[idx].column);
+            line.error(column, err.message)
+        })?;
+        self.write(line, &bytes)?;
+        if let Some((column, relocation)) = fixup {
+            let size = if bytes[0] & 0b100 != 0 { 1 } else { 2 };
+            self.relocate(line, column, size, relocation)?;
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, line: &Line, name: &str, args: &[Operand]) -> Result<(), Diagnostic> {
+        match name {
+            "org" if self.object => {
+                return Err(line.error(
+                    line.statement.column,
+                    "'.org' cannot be used in an object; the linker places sections",
+                ))
+            }
+            "org" => {
+                let arg = single_arg(line, &line.statement, args)?;
+                let address = self.value(line, arg, true)?;
+                if !(0..ADDRESS_SPACE as i64).contains(&address) {
+                    return Err(line.error(
+                        arg.column,
+                        format!("origin {} is outside the address space", address),
+                    ));
+                }
+                self.pc = address as u32;
+            }
+            "byte" => {
+                for arg in args {
+                    let (value, relocation) = self.field(line, arg)?;
+                    if !(-0x80..=0xFF).contains(&value) {
+                        return Err(line.error(
+                            arg.column,
+                            format!("value {} does not fit in 8 bits", value),
+                        ));
+                    }
+                    self.write(line, &[value as u8])?;
+                    if let Some(relocation) = relocation {
+                        self.relocate(line, arg.column, 1, relocation)?;
+                    }
+                }
+            }
+            "short" => {
+                for arg in args {
+                    if let OperandKind::Str(text) = &arg.kind {
+                        self.word_string(line, arg.column, text)?;
+                        continue;
+                    }
+                    let (value, relocation) = self.field(line, arg)?;
+                    if !(-0x8000..=0xFFFF).contains(&value) {
+                        return Err(line.error(
+                            arg.column,
+                            format!("value {} does not fit in 16 bits", value),
+                        ));
+                    }
+                    self.write(line, &(value as u16).to_le_bytes())?;
+                    if let Some(relocation) = relocation {
+                        self.relocate(line, arg.column, 2, relocation)?;
+                    }
+                }
+            }
+            "ascii" => self.ascii(line, args, false)?,
+            "asciz" => self.ascii(line, args, true)?,
+            "align" => self.align(line, args)?,
+            "fill" => self.fill(line, args)?,
+            "space" => self.space(line, args)?,
+            "incbin" => self.incbin(line, args)?,
+            "text" | "rodata" | "data" | "bss" | "section" | "extern" => {
+                self.section_directive(line, name, args)?
+            }
+            _ => {
+                return Err(line.error(
+                    line.statement.column,
+                    format!("unknown directive '.{}'", name),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+fn single_arg<'a>(
+    line: &Line,
+    statement: &Statement,
+    args: &'a [Operand],
+) -> Result<&'a Operand, Diagnostic> {
+    match args {
+        [arg] => Ok(arg),
+        _ => Err(line.error(statement.column, "expected exactly one argument")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{Part, Relocation, Symbol, Target};
+    use std::fs;
+    use std::path::Path;
+
+    // The first error is enough for most tests.
+    fn assemble(source: &str) -> Result<Vec<u8>, Diagnostic> {
+        Assembler::new()
+            .assemble(source)
+            .map_err(|mut errors| errors.remove(0))
+    }
+
+    #[test]
+    fn test_assemble_main_program() {
+        let rom = assemble(
+            "
+            MOV r0, 0x8000
+            PHR r0
+            PLR r1
+            HLT
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            [
+                0b0001_0001,
+                0,
+                0x00,
+                0x80,
+                0b0001_1000,
+                0,
+                0b0010_0000,
+                1,
+                0b0000_1000
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forward_and_backward_labels() {
+        let rom = assemble(
+            "
+            inicio: JMP fim
+            meio:   NOP
+            fim:    JSB meio
+                    JMP inicio
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            [
+                0b1001_1001,
+                0x04,
+                0x00,
+                0x00,
+                0b1010_1001,
+                0x03,
+                0x00,
+                0b1001_1001,
+                0x00,
+                0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn test_org_and_data_directives() {
+        let rom = assemble(
+            "
+            .org 0x4
+            TAMANHO: .const 3
+            dados:  .byte 1, TAMANHO, -1
+                    .short 0xFF00, dados
+            nome:   .ascii \"Olá\"
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            [0, 0, 0, 0, 1, 3, 0xFF, 0x00, 0xFF, 0x04, 0x00, b'O', b'l', 0xE1]
+        );
+    }
+
+    #[test]
+    fn test_conditional_jump_loop() {
+        let rom = assemble(
+            "
+                    MOV r0, 3
+                    MOV r1, 0
+            loop:   ADD r1, 2
+                    DEC r0
+                    JNZ loop
+                    MOV r2, 0x8000
+                    MOV r2*, r1
+                    HLT
+            ",
+        )
+        .unwrap();
+        assert_eq!(&rom[14..18], [0b1010_0001, 1, 0x08, 0x00]);
+    }
+
+    #[test]
+    fn test_expressions() {
+        let rom = assemble(
+            "
+            DEVICE_BASE: .const 0xF000
+            TAMANHO:     .const fim - tabela
+            .org 0x10
+                    MOV r0, DEVICE_BASE + 0x10
+                    MOVB r1, lo(tabela)
+                    MOVB r2, hi(tabela)
+                    MOV r3, TAMANHO / 2
+                    JMP $
+            tabela: .short (1 << 4) | 0x0F, ~0 & 0xFF, -(3 * 2) % 4
+                    .byte ENTRADAS
+            fim:
+            ENTRADAS:   .const (fim - tabela - 1) / 2
+            ",
+        )
+        .unwrap();
+        assert_eq!(&rom[0x10..0x14], [0b0001_0001, 0, 0x10, 0xF0]);
+        assert_eq!(&rom[0x14..0x17], [0b0001_0101, 1, 0x21]);
+        assert_eq!(&rom[0x17..0x1A], [0b0001_0101, 2, 0x00]);
+        assert_eq!(&rom[0x1A..0x1E], [0b0001_0001, 3, 3, 0]);
+        assert_eq!(&rom[0x1E..0x21], [0b1001_1001, 0x1E, 0x00]);
+        assert_eq!(&rom[0x21..], [0x1F, 0x00, 0xFF, 0x00, 0xFE, 0xFF, 3]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let message = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            message("NOP\nFOO R1"),
+            "<source>:2:1: unknown instruction 'FOO'"
+        );
+        assert_eq!(
+            message("  JMP nowhere"),
+            "<source>:1:7: undefined symbol 'nowhere'"
+        );
+        assert_eq!(
+            message("a: NOP\na: NOP"),
+            "<source>:2:1: symbol 'a' is already defined"
+        );
+        assert_eq!(
+            message(".org later\nlater: NOP"),
+            "<source>:1:6: symbol 'later' must be defined before use here"
+        );
+        assert_eq!(
+            message("a: .const b + 1\nb: .const a * 2"),
+            "<source>:1:11: circular definition of constant 'a'"
+        );
+        assert_eq!(
+            message("a: .const b + 1\nNOP"),
+            "<source>:1:11: undefined symbol 'b'"
+        );
+        assert_eq!(
+            message("MOV R0, 4 / (fim - 4)\nfim:"),
+            "<source>:1:9: division by zero"
+        );
+        assert_eq!(
+            message(".org 0xFFFF\nMOV R0, 1"),
+            "<source>:2:1: code exceeds the 64kb address space"
+        );
+        assert_eq!(
+            message("    ADD R0, R1*"),
+            "<source>:1:13: ADD does not accept Reg* here; valid forms: ADD Reg, Reg | ADD Reg, Lit"
+        );
+    }
+
+    #[test]
+    fn test_reports_every_error() {
+        let messages = |source| -> Vec<String> {
+            let errors = Assembler::new().assemble(source).unwrap_err();
+            errors.iter().map(|err| err.to_string()).collect()
+        };
+        // Lines that cannot be read stop the assembly before the passes.
+        assert_eq!(
+            messages("MOV R0,, 1\n.endif\nFOO R1\n.if 1\nNOP"),
+            [
+                "<source>:1:8: expected operand",
+                "<source>:2:1: .endif without .if",
+                "<source>:4:1: .if without .endif",
+            ]
+        );
+        assert_eq!(
+            messages("FOO R1\nJMP nada\nx: .const y\nMOV R0, x\n.org 0x8000\nNOP"),
+            [
+                "<source>:1:1: unknown instruction 'FOO'",
+                "<source>:3:11: undefined symbol 'y'",
+                "<source>:2:5: undefined symbol 'nada'",
+                "<source>:6:1: code at 0x8000 is outside ROM (0x0000-0x7FFF), in RAM",
+            ]
+        );
+        // The body of a bad definition is not assembled as code.
+        assert_eq!(
+            messages(".struct\nx: .byte\n.endstruct\n.macro 1m\nFOO\n.endm\nNOP"),
+            [
+                "<source>:1:8: expected a struct name",
+                "<source>:4:8: expected a macro name",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_report_shows_source_line() {
+        let err = assemble("NOP\n\tMOV R0,  nada ; comentário").unwrap_err();
+        assert_eq!(
+            err.report(),
+            "<source>:2:11: undefined symbol 'nada'\n 2 | \tMOV R0,  nada ; comentário\n   | \t         ^^^^"
+        );
+
+        let err = assemble(".macro m\n  FOO\n.endm\nm").unwrap_err();
+        assert_eq!(
+            err.report(),
+            "<source>:2:3: unknown instruction 'FOO'\n 2 |   FOO\n   |   ^^^\n    in expansion of macro 'm' at <source>:4:1"
+        );
+    }
+
+    #[test]
+    fn test_warnings() {
+        let mut assembler = Assembler::new();
+        let rom = assembler
+            .assemble(
+                "inicio: MOVB R1, 0x1234\n  laco: JMP inicio\nusado: .const 1\n:\nMOVB R0, -1",
+            )
+            .unwrap();
+        assert_eq!(&rom[..3], [0b0001_0101, 1, 0x34]);
+        let warnings: Vec<String> = assembler.warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            [
+                "<source>:1:18: warning: literal 0x1234 truncated to 8 bits (0x34)",
+                "<source>:2:3: warning: label 'laco' is never used",
+            ]
+        );
+        assert_eq!(
+            assemble("MOVB R0, 0x10000").unwrap_err().to_string(),
+            "<source>:1:10: value 65536 does not fit in 8 bits"
+        );
+    }
+
+    #[test]
+    fn test_placement() {
+        // Labels may name RAM as long as nothing is written there.
+        let rom = assemble(".org 0x8000\ncontador:\n.org 0x10\nMOV R0, contador").unwrap();
+        assert_eq!(rom.len(), 0x14);
+        assert!(assemble(".org 0x7FFE\n.short 1").is_ok());
+
+        let message = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            message(".org 0x8000\nNOP"),
+            "<source>:2:1: code at 0x8000 is outside ROM (0x0000-0x7FFF), in RAM"
+        );
+        assert_eq!(
+            message(".org 0xF000\nHLT"),
+            "<source>:2:1: code at 0xF000 is outside ROM (0x0000-0x7FFF), in Devices"
+        );
+        assert_eq!(
+            message(".org 0xE000\n.byte 1"),
+            "<source>:2:1: initialized data at 0xE000 is in STACK, which is not loaded \
+             from the ROM image; name the address with '.const' instead"
+        );
+        assert_eq!(
+            message(".org 0x7FFE\n.ascii \"abc\""),
+            "<source>:2:1: program exceeds the 32768 bytes of ROM"
+        );
+        assert_eq!(
+            message("MOV R0, 1\nNOP\n.org 0x2\nHLT"),
+            "<source>:4:1: 0x0002-0x0002 overlaps the bytes placed at 0x0000-0x0004 by <source>:1:1"
+        );
+        assert_eq!(
+            message(".org 0x10\nNOP\n.org 0xE\nMOV R0, 1"),
+            "<source>:4:1: 0x000E-0x0011 overlaps the bytes placed at 0x0010-0x0010 by <source>:2:1"
+        );
+    }
+
+    #[test]
+    fn test_struct_and_enum() {
+        let rom = assemble(
+            "
+            .enum Tipo
+                HEROI
+                INIMIGO
+                CHEFE = 10
+                FINAL
+            .endenum
+            .enum
+                VAZIO = 0x100
+            .endenum
+
+            .struct Vetor
+            x:      .short
+            y:      .short
+            .endstruct
+
+            .struct Jogador
+            tipo:   .byte
+                    .align 2
+            pos:    .space Vetor.size
+            nome:   .byte 5
+            vidas:  .short 2
+            .endstruct
+
+            jogador: .const 0x8000
+            inicio:
+            .laco:  MOV R1, jogador + Jogador.pos + Vetor.y
+                    .byte Jogador.size, Jogador.vidas, Tipo.INIMIGO, Tipo.FINAL, hi(VAZIO)
+                    JMP .laco
+            .if Jogador.size != 15
+                    HLT
+            .endif
+            ",
+        )
+        .unwrap();
+        // MOV R1, jogador + Jogador.pos + Vetor.y
+        assert_eq!(rom[..4], [0b0001_0001, 1, 0x04, 0x80]);
+        assert_eq!(rom[4..9], [15, 11, 1, 11, 1]);
+        assert_eq!(rom.len(), 12);
+
+        let message = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            message(".struct P\nx: .byte\nNOP\n.endstruct"),
+            "<source>:3:1: expected .byte, .short, .space or .align inside .struct"
+        );
+        assert_eq!(
+            message(".struct P\nx: .space N\n.endstruct\nN: .const 2"),
+            "<source>:2:11: symbol 'N' must be a constant defined before .struct"
+        );
+        assert_eq!(
+            message(".struct P\nx: .byte\nx: .short\n.endstruct"),
+            "<source>:3:1: symbol 'P.x' is already defined"
+        );
+        assert_eq!(
+            message("  .struct P\nx: .byte"),
+            "<source>:1:3: struct 'P' is missing .endstruct"
+        );
+        assert_eq!(
+            message(".struct\n.endstruct"),
+            "<source>:1:8: expected a struct name"
+        );
+        assert_eq!(
+            message(".enum\nA = B\n.endenum\nB: .const 1"),
+            "<source>:2:1: symbol 'B' must be a constant defined before .enum"
+        );
+        assert_eq!(
+            message(".enum\nA = 0x7FFFFFFFFFFFFFFF\nB\n.endenum"),
+            "<source>:2:1: value 9223372036854775807 does not fit in 16 bits"
+        );
+        assert_eq!(
+            message(".enum\nA = 0xFFFF\nB\n.endenum"),
+            "<source>:3:1: value 65536 does not fit in 16 bits"
+        );
+        assert_eq!(message(".endenum"), "<source>:1:1: .endenum without .enum");
+    }
+
+    #[test]
+    fn test_local_and_anonymous_labels() {
+        let rom = assemble(
+            "
+            .macro esperar reg
+            .laco:  DEC reg
+                    JNZ .laco
+            .endm
+            rotina1: MOV R0, 3
+            .laco:   esperar R0
+                     esperar R1
+                     JNZ .laco
+                     JMP .fim
+            .fim:    RSB
+            rotina2: MOV R1, 2
+            .laco:   DEC R1
+                     JNZ .laco
+                     JMP rotina1.fim
+            :        DEC R2
+                     JNZ :-
+                     JMP :+
+            :        JMP :--
+            :        HLT
+            ",
+        )
+        .unwrap();
+        let expected = assemble(
+            "
+            rotina1: MOV R0, 3
+            laco1:   DEC R0
+                     JNZ laco1
+            laco2:   DEC R1
+                     JNZ laco2
+                     JNZ laco1
+                     JMP fim1
+            fim1:    RSB
+            rotina2: MOV R1, 2
+            laco3:   DEC R1
+                     JNZ laco3
+                     JMP fim1
+            anon1:   DEC R2
+                     JNZ anon1
+                     JMP anon2
+            anon2:   JMP anon1
+            anon3:   HLT
+            ",
+        )
+        .unwrap();
+        assert_eq!(rom, expected);
+
+        let message = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            message("a: NOP\n.b: NOP\n.b: NOP"),
+            "<source>:3:1: symbol 'a.b' is already defined"
+        );
+        assert_eq!(
+            message("a: NOP\nb: JMP .c\na.c: NOP"),
+            "<source>:2:8: undefined symbol 'b.c'"
+        );
+        assert_eq!(
+            message(": NOP\nJMP :--"),
+            "<source>:2:5: no anonymous label for ':--'"
+        );
+        assert_eq!(
+            message(".a: NOP\nJMP .a"),
+            "<source>:1:1: local label '.a' before any global label"
+        );
+    }
+
+    #[test]
+    fn test_conditional_assembly() {
+        let source = "
+            .ifdef DEBUG
+            CONSOLE: .const 0xF000
+                    MOV R0, CONSOLE
+            .else
+                    NOP
+            .endif
+            .if NIVEL >= 2 && !(NIVEL == 3)
+                    .if 0
+                    INC R1
+                    .else
+                    DEC R1
+                    .endif
+            .endif
+            .ifndef CONSOLE
+                    HLT
+            .endif
+                    .byte NIVEL
+            ";
+        let build = |defines: &[&str]| {
+            let mut assembler = Assembler::new();
+            for define in defines {
+                assembler.add_define(define).unwrap();
+            }
+            assembler.assemble(source).unwrap()
+        };
+        assert_eq!(build(&["NIVEL=0"]), [0b0000_0000, 0b0000_1000, 0]);
+        assert_eq!(
+            build(&["DEBUG", "NIVEL=0x2"]),
+            [0b0001_0001, 0, 0x00, 0xF0, 0b0101_1000, 1, 2]
+        );
+
+        let mut assembler = Assembler::new();
+        assert_eq!(
+            assembler.add_define("R1=2"),
+            Err("invalid symbol name 'R1'".to_string())
+        );
+        assert_eq!(
+            assembler.add_define("X=abc"),
+            Err("invalid value 'abc' for 'X'".to_string())
+        );
+        assembler.add_define("X").unwrap();
+        assert_eq!(
+            assembler.assemble("X: NOP").unwrap_err()[0].to_string(),
+            "<source>:1:1: symbol 'X' is already defined"
+        );
+
+        let message = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            message("NOP\n  .if 1\nNOP"),
+            "<source>:2:3: .if without .endif"
+        );
+        assert_eq!(message(".endif"), "<source>:1:1: .endif without .if");
+        assert_eq!(
+            message(".if 1\n.else\n.else\n.endif"),
+            "<source>:3:1: duplicate .else"
+        );
+        assert_eq!(
+            message(".if fim\nfim: NOP\n.endif"),
+            "<source>:1:5: symbol 'fim' must be a constant defined before .if"
+        );
+        assert_eq!(
+            message(".ifdef 1\n.endif"),
+            "<source>:1:8: expected a symbol name"
+        );
+    }
+
+    #[test]
+    fn test_structured_control_flow() {
+        let rom = assemble(
+            "
+                    MOV R0, 0
+                    MOV R1, 0
+            .while R0 != 10
+                    ADD R1, R0
+                    INC R0
+            .endwhile
+            ",
+        )
+        .unwrap();
+        // CMP R0, 10 then JZ past the loop.
+        assert_eq!(
+            &rom[8..16],
+            [0b1001_0001, 0, 10, 0, 0b1010_0001, 0, 0x17, 0x00]
+        );
+        // `<` tests the borrow, so the comparison is unsigned.
+        assert_eq!(
+            assemble(".while R0 < 40000\n.endwhile").unwrap()[4..6],
+            [0b1010_0001, 5]
+        );
+
+        let message = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            message(
+                "NOP
+.while R0 != 1
+NOP"
+            ),
+            "<source>:2:1: .while without .endwhile"
+        );
+        assert_eq!(
+            message(
+                ".repeat
+.endif"
+            ),
+            "<source>:2:1: .until expected before .endif"
+        );
+        assert_eq!(
+            message(".endwhile"),
+            "<source>:1:1: .endwhile without .while"
+        );
+        let errors = Assembler::new()
+            .assemble("  laco: .while R0 != 1\nNOP\n.endwhile")
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            "<source>:1:3: structured blocks do not take a label on the same line"
+        );
+        assert_eq!(
+            message(
+                ".while R0 > 1
+.endwhile"
+            ),
+            "<source>:1:11: expected a comparison with ==, !=, < or >="
+        );
+        assert_eq!(
+            message(
+                ".ifz R1*
+.endif"
+            ),
+            "<source>:1:6: CMP does not accept Reg* here; valid forms: CMP Reg, Reg | CMP Reg, Lit"
+        );
+    }
+
+    #[test]
+    fn test_object_sections_and_relocations() {
+        let object = Assembler::new()
+            .assemble_object(
+                "
+                .extern imprime
+                .global inicio, contador
+                inicio: MOV r0, mensagem
+                        MOVB r1, hi(contador + 1)
+                        JSB imprime
+                TAMANHO: .const fim - mensagem
+                FIM_TEXTO: .const mensagem + TAMANHO
+                .rodata
+                mensagem: .ascii \"Oi\"
+                fim:      .short FIM_TEXTO, 7
+                .bss
+                contador: .short 0
+                .text
+                        JMP inicio
+                ",
+            )
+            .unwrap();
+
+        let [text, rodata, bss] = &object.sections[..] else {
+            panic!("unexpected sections {:?}", object.sections);
+        };
+        assert_eq!(text.name, ".text");
+        assert_eq!(
+            text.data,
+            [
+                0b0001_0001,
+                0,
+                0,
+                0,
+                0b0001_0101,
+                1,
+                0,
+                0b1010_1001,
+                0,
+                0,
+                0b1001_1001,
+                0,
+                0
+            ]
+        );
+        let relocation = |offset, size, part, target, addend| Relocation {
+            offset,
+            size,
+            part,
+            target,
+            addend,
+        };
+        let section = |name: &str| Target::Section(name.to_string());
+        assert_eq!(
+            text.relocations,
+            [
+                relocation(2, 2, Part::Full, section(".rodata"), 0),
+                relocation(6, 1, Part::Hi, section(".bss"), 1),
+                relocation(8, 2, Part::Full, Target::Symbol("imprime".to_string()), 0),
+                relocation(11, 2, Part::Full, section(".text"), 0),
+            ]
+        );
+        assert_eq!(rodata.data, [b'O', b'i', 0, 0, 7, 0]);
+        assert_eq!(
+            rodata.relocations,
+            [relocation(2, 2, Part::Full, section(".rodata"), 2)]
+        );
+        assert_eq!((bss.size, bss.data.len()), (2, 0));
+        assert_eq!(
+            object.exports,
+            [
+                Symbol {
+                    name: "inicio".to_string(),
+                    section: Some(".text".to_string()),
+                    value: 0
+                },
+                Symbol {
+                    name: "contador".to_string(),
+                    section: Some(".bss".to_string()),
+                    value: 0
+                },
+            ]
+        );
+        assert_eq!(object.imports, ["imprime"]);
+    }
+
+    #[test]
+    fn test_object_errors() {
+        let message = |source| Assembler::new().assemble_object(source).unwrap_err()[0].to_string();
+        assert_eq!(
+            message("a: NOP\n.byte a"),
+            "<source>:2:7: relocatable address does not fit in 8 bits; use lo() or hi()"
+        );
+        assert_eq!(
+            message(".extern x\n.short x * 2"),
+            "<source>:2:8: expression cannot be relocated"
+        );
+        assert_eq!(
+            message(".bss\n.byte 1"),
+            "<source>:2:1: only zero bytes can be placed in .bss"
+        );
+        assert_eq!(
+            message(".org 0x100"),
+            "<source>:1:1: '.org' cannot be used in an object; the linker places sections"
+        );
+        assert_eq!(
+            message(".section dados"),
+            "<source>:1:10: unknown section '.dados'; expected .text, .rodata, .data or .bss"
+        );
+        assert_eq!(
+            message(".global nada"),
+            "<source>:1:9: undefined symbol 'nada'"
+        );
+        assert_eq!(
+            message(".extern x\n.global x"),
+            "<source>:2:9: cannot export external symbol 'x'"
+        );
+        assert_eq!(
+            assemble(".data").unwrap_err().to_string(),
+            "<source>:1:1: '.data' is only available when assembling an object"
+        );
+    }
+
+    #[test]
+    fn test_listing() {
+        let mut assembler = Assembler::new();
+        assembler
+            .assemble(
+                "BASE: .const 0xF000\n\
+                 .macro salvar a\n\
+                 PHR a\n\
+                 .endm\n\
+                 inicio: MOV R0, BASE\n\
+                 salvar R0\n\
+                 \n\
+                 .org 0x10\n\
+                 msg: .ascii \"Ola!!\"\n\
+                 JMP inicio",
+            )
+            .unwrap();
+        assert_eq!(
+            assembler.listing(),
+            "; <source>\n\
+             =F000                   1  BASE: .const 0xF000\n\
+             0000   11 00 00 F0      5  inicio: MOV R0, BASE\n\
+             \x20                       6  salvar R0\n\
+             0004   18 00            3+ PHR R0\n\
+             \x20                       7\n\
+             0010                    8  .org 0x10\n\
+             0010   4F 6C 61 21      9  msg: .ascii \"Ola!!\"\n\
+             0014   21\n\
+             0015   99 00 00        10  JMP inicio\n\
+             \n\
+             ; symbols\n\
+             BASE                     F000   <source>:1\n\
+             inicio                   0000   <source>:5\n\
+             msg                      0010   <source>:9\n\
+             \n\
+             ; cross-reference\n\
+             BASE                     <source>:5\n\
+             inicio                   <source>:10\n\
+             msg                      -\n"
+        );
+    }
+
+    #[test]
+    fn test_macros() {
+        let rom = assemble(
+            "
+            .macro salvar a, b
+                    PHR a
+                    PHR b
+            .endm
+            .macro esperar reg, vezes=3 ; conta ate zero
+                    MOV reg, vezes
+            laco:   DEC reg
+                    JNZ laco
+            .endm
+            inicio: salvar R1, R2
+                    esperar R0
+                    ESPERAR R3, (1 + 1)
+                    JMP inicio
+            ",
+        )
+        .unwrap();
+        let expected = assemble(
+            "
+            inicio: PHR R1
+                    PHR R2
+                    MOV R0, 3
+            laco1:  DEC R0
+                    JNZ laco1
+                    MOV R3, (1 + 1)
+            laco2:  DEC R3
+                    JNZ laco2
+                    JMP inicio
+            ",
+        )
+        .unwrap();
+        assert_eq!(rom, expected);
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let message = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            message("NOP\n.macro m a\nNOP"),
+            "<source>:2:1: macro 'm' is missing .endm"
+        );
+        assert_eq!(message("  .endm"), "<source>:1:3: .endm without .macro");
+        assert_eq!(
+            message(".macro MOV a\n.endm"),
+            "<source>:1:8: 'MOV' is an instruction and cannot be a macro name"
+        );
+        assert_eq!(
+            message(".macro m a, a\n.endm"),
+            "<source>:1:13: duplicate macro parameter 'a'"
+        );
+        assert_eq!(
+            message(".macro m a\n.endm\nm 1, 2"),
+            "<source>:3:1: macro 'm' takes 1 argument(s), found 2"
+        );
+        assert_eq!(
+            message(".macro m reg\n  INC reg, 1\n.endm\nNOP\n  m R1"),
+            "<source>:2:3: expected 1 operand(s), found 2\n    in expansion of macro 'm' at <source>:5:3"
+        );
+        let err = assemble(".macro a\nb\n.endm\n.macro b\na\n.endm\na").unwrap_err();
+        assert_eq!(err.message, "macro 'a' expands too deeply");
+        let text = err.to_string();
+        assert_eq!(text.lines().count(), 1 + 2 * BACKTRACE_ENDS + 1, "{}", text);
+        assert!(text.contains("\n    ... 56 more\n"), "{}", text);
+        assert!(
+            text.ends_with("in expansion of macro 'a' at <source>:7:1"),
+            "{}",
+            text
+        );
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cupana-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_file(dir: &Path, name: &str, text: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_include_search_paths() {
+        let dir = temp_dir("include");
+        let main = write_file(
+            &dir,
+            "src/main.casm",
+            ".include \"lib/util.casm\"\n.include \"driver.casm\"\nJMP util\n",
+        );
+        write_file(
+            &dir,
+            "src/lib/util.casm",
+            ".include \"consts.casm\"\nutil: MOV R0, VALOR\n",
+        );
+        write_file(&dir, "src/lib/consts.casm", "VALOR: .const 7\n");
+        write_file(&dir, "drivers/driver.casm", "HLT\n");
+
+        let mut assembler = Assembler::new();
+        assembler.add_include_dir(dir.join("drivers"));
+        let rom = assembler.assemble_file(&main).unwrap();
+        assert_eq!(
+            rom,
+            [0b0001_0001, 0, 7, 0, 0b0000_1000, 0b1001_1001, 0x00, 0x00]
+        );
+
+        let err = Assembler::new().assemble_file(&main).unwrap_err().remove(0);
+        assert_eq!(err.message, "cannot find include file 'driver.casm'");
+        assert_eq!(err.location.line, 2);
+        assert_eq!(err.location.column, 10);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_errors_report_chain() {
+        let dir = temp_dir("include-chain");
+        let main = write_file(&dir, "main.casm", "NOP\n  .include \"a.casm\"\n");
+        let a = write_file(&dir, "a.casm", ".include \"b.casm\"\n");
+        let b = write_file(&dir, "b.casm", "NOP\nMOV R0, R1, R2\n");
+
+        let err = Assembler::new().assemble_file(&main).unwrap_err().remove(0);
+        assert_eq!(err.location.file, b.display().to_string());
+        assert_eq!((err.location.line, err.location.column), (2, 1));
+        assert_eq!(
+            err.backtrace,
+            [
+                Origin::Include(Location {
+                    file: a.display().to_string(),
+                    line: 1,
+                    column: 1
+                }),
+                Origin::Include(Location {
+                    file: main.display().to_string(),
+                    line: 2,
+                    column: 3
+                }),
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{}:2:1: expected 2 operand(s), found 3\n    included from {}:1:1\n    included from {}:2:3",
+                b.display(),
+                a.display(),
+                main.display()
+            )
+        );
+
+        write_file(&dir, "b.casm", ".include \"a.casm\"\n");
+        let err = Assembler::new().assemble_file(&main).unwrap_err().remove(0);
+        assert_eq!(
+            err.message,
+            format!(
+                "include cycle: {} -> {} -> {}",
+                a.display(),
+                b.display(),
+                a.display()
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_data_layout_directives() {
+        let rom = assemble(
+            "
+            mensagem: .short \"Ola!\"
+                      .asciz \"a\\n\\x41\", \"\"
+                      .align 4, 0xFF
+            tabela:   .fill 3, 7
+                      .space 2
+                      .byte 1
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            [
+                b'O', 0, b'l', 0, b'a', 0, b'!', 0, 0, 0, // .short
+                b'a', b'\n', b'A', 0, 0,    // .asciz
+                0xFF, // .align
+                7, 7, 7, 0, 0, 1,
+            ]
+        );
+
+        // `.space` may reserve RAM, since nothing is written there.
+        let rom =
+            assemble(".org 0x8000\nbuffer: .space 64\nfim:\n.org 0\nMOV R0, fim - buffer").unwrap();
+        assert_eq!(rom, [0b0001_0001, 0, 64, 0]);
+
+        let message = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            message(".align 3"),
+            "<source>:1:8: alignment 3 is not a power of two up to 0x8000"
+        );
+        assert_eq!(
+            message(".fill tamanho\ntamanho: .const 2"),
+            "<source>:1:7: symbol 'tamanho' must be defined before use here"
+        );
+        assert_eq!(
+            message(".fill 2, 0x100"),
+            "<source>:1:10: value 256 does not fit in 8 bits"
+        );
+        assert_eq!(
+            message(".short \"\u{1F600}\""),
+            "<source>:1:8: character '\u{1F600}' cannot be encoded in a word"
+        );
+        assert_eq!(
+            message(".asciz \"\\q\""),
+            "<source>:1:9: unknown escape sequence '\\q'"
+        );
+    }
+
+    #[test]
+    fn test_incbin() {
+        let dir = temp_dir("incbin");
+        fs::write(dir.join("fonte.bin"), [1, 2, 3, 4, 5]).unwrap();
+        let main = write_file(
+            &dir,
+            "main.casm",
+            ".incbin \"fonte.bin\"\n.incbin \"fonte.bin\", 1, 2\n.incbin \"fonte.bin\", 4\n",
+        );
+        let rom = Assembler::new().assemble_file(&main).unwrap();
+        assert_eq!(rom, [1, 2, 3, 4, 5, 2, 3, 5]);
+
+        write_file(&dir, "main.casm", ".incbin \"fonte.bin\", 4, 2\n");
+        let err = Assembler::new().assemble_file(&main).unwrap_err().remove(0);
+        assert_eq!(
+            err.message,
+            format!(
+                "range 4..6 is outside '{}', which has 5 bytes",
+                dir.join("fonte.bin").display()
+            )
+        );
+        assert_eq!(err.location.column, 22);
+
+        write_file(&dir, "main.casm", ".incbin \"sprites.bin\"\n");
+        let err = Assembler::new().assemble_file(&main).unwrap_err().remove(0);
+        assert_eq!(err.message, "cannot find file 'sprites.bin'");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_peephole() {
+        let optimized = |source: &str| {
+            let mut assembler = Assembler::new();
+            assembler.set_optimize(true);
+            assembler.assemble(source).unwrap()
+        };
+        let rom = optimized(
+            "
+                    MOV R1, R1
+            MASK:   .const 0x0F
+                    MOV R2, 0x20        ; MOVB R2, 0x20
+                    ADD R2, 1           ; INC R2
+                    CMP R2, 0           ; OR R2, R2
+                    JZ fim
+                    AND R2, MASK        ; ANDB: SUB sets the flags again
+                    SUB R2, 1           ; DEC R2
+                    MOV R0, 0           ; MOVB: JNZ still tests the DEC
+                    JNZ fim
+                    MOV R3, 0           ; XOR R3, R3
+                    ADD R3, 0xFFFF      ; DEC R3: JZ does not test overflow
+                    JZ fim
+                    SUB R4, -1          ; kept for JO
+                    JO fim
+                    PHR R5
+                    PLR R5
+                    MOV R6, R7
+                    MOV R7, R6
+                    PHR PC              ; kept: pairs on PC or SP are left alone
+                    PLR PC
+                    MOV R10, SP
+                    MOV SP, R10
+                    OR R8, 0            ; HLT does not test the flags
+            fim:    HLT
+            rotina: XOR R9, 0           ; kept: the caller may test the flags
+                    RSB
+            ",
+        );
+        let expected = assemble(
+            "
+                    MOVB R2, 0x20
+                    INC R2
+                    OR R2, R2
+                    JZ fim
+                    ANDB R2, 0x0F
+                    DEC R2
+                    MOVB R0, 0
+                    JNZ fim
+                    XOR R3, R3
+                    DEC R3
+                    JZ fim
+                    SUB R4, -1
+                    JO fim
+                    MOV R6, R7
+                    PHR PC
+                    PLR PC
+                    MOV R10, SP
+                    MOV SP, R10
+            fim:    HLT
+                    XOR R9, 0
+                    RSB
+            ",
+        )
+        .unwrap();
+        assert_eq!(rom, expected);
+
+        // The optimized loop is shorter.
+        let source = "
+                    MOV R0, 0
+                    MOV R1, 10
+            laco:   ADD R0, R1
+                    SUB R1, 1
+                    JNZ laco
+                    MOV R2, 0x8000
+                    MOV R2*, R0
+                    HLT
+            ";
+        let rom = optimized(source);
+        assert_eq!(rom.len(), assemble(source).unwrap().len() - 5);
+    }
+
+    #[test]
+    fn test_assemble_sources() {
+        let sources = Sources::new()
+            .add(
+                "src/main.casm",
+                ".include \"io.inc\"\ninicio: MOV R0, PORTA\n        HLT\ndados:  .incbin \"../res/fonte.bin\", 1\n",
+            )
+            .add("inc/io.inc", "PORTA: .const BASE + 2\n")
+            .add("res/fonte.bin", [1, 2, 3]);
+        let options = Options {
+            include_dirs: vec![PathBuf::from("inc")],
+            defines: vec!["BASE=0xF000".to_string()],
+            optimize: false,
+        };
+        let image = super::assemble(&sources, &options).unwrap();
+        assert_eq!(image.rom, [0x11, 0, 0x02, 0xF0, 0x08, 2, 3]);
+        assert_eq!(image.symbols["PORTA"], 0xF002);
+        assert_eq!(image.symbols["dados"], 5);
+        assert_eq!(image.warnings.len(), 2);
+        assert_eq!(image.warnings[0].message, "label 'inicio' is never used");
+
+        let errors = super::assemble(&Sources::from("MOV R0, PORTA"), &options).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "<source>:1:9: undefined symbol 'PORTA'"
+        );
+
+        let options = Options {
+            defines: vec!["R1=2".to_string()],
+            ..Options::default()
+        };
+        let errors = super::assemble(&sources, &options).unwrap_err();
+        assert_eq!(errors[0].to_string(), "-D R1=2: invalid symbol name 'R1'");
+        let errors = super::assemble(&Sources::new(), &options).unwrap_err();
+        assert_eq!(errors[0].to_string(), "<sources>: no source file given");
+    }
+}
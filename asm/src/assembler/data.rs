@@ -0,0 +1,177 @@
+use super::parser::{Operand, OperandKind};
+use super::source::{resolve, Line};
+use super::{Assembler, Diagnostic, ADDRESS_SPACE};
+
+impl Assembler {
+    // `.ascii` and `.asciz`, which adds a zero byte after each string.
+    pub(super) fn ascii(
+        &mut self,
+        line: &Line,
+        args: &[Operand],
+        terminated: bool,
+    ) -> Result<(), Diagnostic> {
+        for arg in args {
+            let OperandKind::Str(text) = &arg.kind else {
+                return Err(line.error(arg.column, "expected a string"));
+            };
+            let mut bytes = Vec::new();
+            for c in text.chars() {
+                if c as u32 > 0xFF {
+                    return Err(line.error(
+                        arg.column,
+                        format!("character '{}' cannot be encoded in a byte", c),
+                    ));
+                }
+                bytes.push(c as u8);
+            }
+            if terminated {
+                bytes.push(0);
+            }
+            self.write(line, &bytes)?;
+        }
+        Ok(())
+    }
+
+    // A string given to `.short`: one word per character and a null word.
+    pub(super) fn word_string(
+        &mut self,
+        line: &Line,
+        column: usize,
+        text: &str,
+    ) -> Result<(), Diagnostic> {
+        let mut bytes = Vec::new();
+        for c in text.chars().chain(['\0']) {
+            if c as u32 > 0xFFFF {
+                return Err(line.error(
+                    column,
+                    format!("character '{}' cannot be encoded in a word", c),
+                ));
+            }
+            bytes.extend_from_slice(&(c as u16).to_le_bytes());
+        }
+        self.write(line, &bytes)
+    }
+
+    // `.align n[, value]` pads with `value` up to the next multiple of `n`.
+    pub(super) fn align(&mut self, line: &Line, args: &[Operand]) -> Result<(), Diagnostic> {
+        let (alignment, fill) = match args {
+            [alignment] => (alignment, None),
+            [alignment, fill] => (alignment, Some(fill)),
+            _ => return Err(line.error(line.statement.column, "expected 1 or 2 arguments")),
+        };
+        let value = self.value(line, alignment, true)?;
+        if value < 1 || value > ADDRESS_SPACE as i64 / 2 || value & (value - 1) != 0 {
+            return Err(line.error(
+                alignment.column,
+                format!("alignment {} is not a power of two up to 0x8000", value),
+            ));
+        }
+        let fill = match fill {
+            Some(fill) => self.byte(line, fill)?,
+            None => 0,
+        };
+        if let Some(section) = self.section {
+            let state = &mut self.sections[section];
+            state.align = state.align.max(value as usize);
+        }
+        let padding = (value as u32 - self.pc % value as u32) % value as u32;
+        self.write(line, &vec![fill; padding as usize])
+    }
+
+    // `.fill count[, value]` writes `count` copies of a byte.
+    pub(super) fn fill(&mut self, line: &Line, args: &[Operand]) -> Result<(), Diagnostic> {
+        let (count, fill) = match args {
+            [count] => (count, None),
+            [count, fill] => (count, Some(fill)),
+            _ => return Err(line.error(line.statement.column, "expected 1 or 2 arguments")),
+        };
+        let count = self.count(line, count)?;
+        let fill = match fill {
+            Some(fill) => self.byte(line, fill)?,
+            None => 0,
+        };
+        self.write(line, &vec![fill; count])
+    }
+
+    // `.space n` reserves `n` bytes without initializing them, so it may be
+    // used for RAM buffers.
+    pub(super) fn space(&mut self, line: &Line, args: &[Operand]) -> Result<(), Diagnostic> {
+        let arg = super::single_arg(line, &line.statement, args)?;
+        let count = self.count(line, arg)?;
+        let end = self.pc + count as u32;
+        if end > ADDRESS_SPACE {
+            return Err(line.error(1, "code exceeds the 64kb address space"));
+        }
+        self.pc = end;
+        Ok(())
+    }
+
+    // `.incbin "file"[, offset[, length]]` copies the bytes of a host file.
+    pub(super) fn incbin(&mut self, line: &Line, args: &[Operand]) -> Result<(), Diagnostic> {
+        let Some((
+            Operand {
+                kind: OperandKind::Str(target),
+                column,
+            },
+            range,
+        )) = args.split_first()
+        else {
+            return Err(line.error(line.statement.column, "expected a file name string"));
+        };
+        if range.len() > 2 {
+            return Err(line.error(range[2].column, "unexpected argument"));
+        }
+
+        let Some(path) = resolve(&self.include_dirs, &*self.files, &line.file, target) else {
+            return Err(line.error(*column, format!("cannot find file '{}'", target)));
+        };
+        let bytes = self.files.read(&path).map_err(|err| {
+            line.error(
+                *column,
+                format!("cannot read '{}': {}", path.display(), err),
+            )
+        })?;
+
+        let offset = match range.first() {
+            Some(offset) => self.count(line, offset)?,
+            None => 0,
+        };
+        let length = match range.get(1) {
+            Some(length) => self.count(line, length)?,
+            None => bytes.len().saturating_sub(offset),
+        };
+        let Some(bytes) = bytes.get(offset..offset + length) else {
+            return Err(line.error(
+                range.first().map_or(*column, |arg| arg.column),
+                format!(
+                    "range {}..{} is outside '{}', which has {} bytes",
+                    offset,
+                    offset + length,
+                    path.display(),
+                    bytes.len()
+                ),
+            ));
+        };
+        self.write(line, bytes)
+    }
+
+    fn byte(&self, line: &Line, arg: &Operand) -> Result<u8, Diagnostic> {
+        let value = self.value(line, arg, false)?;
+        if !(-0x80..=0xFF).contains(&value) {
+            return Err(line.error(
+                arg.column,
+                format!("value {} does not fit in 8 bits", value),
+            ));
+        }
+        Ok(value as u8)
+    }
+
+    // A size, which has to be known in the first pass.
+    fn count(&self, line: &Line, arg: &Operand) -> Result<usize, Diagnostic> {
+        let value = self.value(line, arg, true)?;
+        if !(0..=ADDRESS_SPACE as i64).contains(&value) {
+            return Err(line.error(arg.column, format!("invalid size {}", value)));
+        }
+        Ok(value as usize)
+    }
+}
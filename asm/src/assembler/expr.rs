@@ -0,0 +1,296 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    Lo,
+    Hi,
+    LogicalNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Current,
+    Anonymous(i64),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExprError {
+    Undefined(String),
+    Invalid(String),
+}
+
+// `constant + coefficient * base + ...`, where each base is an address that only
+// the linker knows. Used to turn expressions into relocations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Linear<K> {
+    pub constant: i64,
+    pub terms: Vec<(K, i64)>,
+}
+
+impl<K: Clone + PartialEq> Linear<K> {
+    pub fn constant(value: i64) -> Self {
+        Linear {
+            constant: value,
+            terms: Vec::new(),
+        }
+    }
+
+    pub fn base(base: K, offset: i64) -> Self {
+        Linear {
+            constant: offset,
+            terms: vec![(base, 1)],
+        }
+    }
+
+    fn add(mut self, other: Self, sign: i64) -> Self {
+        self.constant = self
+            .constant
+            .wrapping_add(other.constant.wrapping_mul(sign));
+        for (base, coefficient) in other.terms {
+            match self.terms.iter_mut().find(|(b, _)| *b == base) {
+                Some((_, c)) => *c += coefficient * sign,
+                None => self.terms.push((base, coefficient * sign)),
+            }
+        }
+        self.terms.retain(|(_, c)| *c != 0);
+        self
+    }
+
+    fn scale(mut self, factor: i64) -> Self {
+        self.constant = self.constant.wrapping_mul(factor);
+        for (_, coefficient) in &mut self.terms {
+            *coefficient *= factor;
+        }
+        self.terms.retain(|(_, c)| *c != 0);
+        self
+    }
+
+    fn number(&self) -> Result<i64, ExprError> {
+        match self.terms.is_empty() {
+            true => Ok(self.constant),
+            false => Err(ExprError::Invalid(
+                "expression cannot be relocated".to_string(),
+            )),
+        }
+    }
+}
+
+impl Expr {
+    pub fn symbols<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Expr::Symbol(name) => out.push(name),
+            Expr::Unary(_, inner) => inner.symbols(out),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.symbols(out);
+                rhs.symbols(out);
+            }
+            _ => {}
+        }
+    }
+
+    // Like `eval`, but symbols may stand for relocatable addresses. Only sums,
+    // differences and multiples of them are allowed.
+    pub fn eval_linear<K: Clone + PartialEq>(
+        &self,
+        lookup: &dyn Fn(&str) -> Option<Linear<K>>,
+        pc: &Linear<K>,
+    ) -> Result<Linear<K>, ExprError> {
+        let number = |value| Box::new(Expr::Number(value));
+        let none = |_: &str| None;
+        match self {
+            Expr::Symbol(name) => lookup(name).ok_or_else(|| ExprError::Undefined(name.clone())),
+            Expr::Current => Ok(pc.clone()),
+            Expr::Unary(UnaryOp::Neg, inner) => Ok(inner.eval_linear(lookup, pc)?.scale(-1)),
+            Expr::Unary(op, inner) => {
+                let value = inner.eval_linear(lookup, pc)?.number()?;
+                Expr::Unary(*op, number(value))
+                    .eval(&none, 0)
+                    .map(Linear::constant)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval_linear(lookup, pc)?;
+                let rhs = rhs.eval_linear(lookup, pc)?;
+                match op {
+                    BinaryOp::Add => Ok(lhs.add(rhs, 1)),
+                    BinaryOp::Sub => Ok(lhs.add(rhs, -1)),
+                    BinaryOp::Mul if lhs.terms.is_empty() => Ok(rhs.scale(lhs.constant)),
+                    BinaryOp::Mul if rhs.terms.is_empty() => Ok(lhs.scale(rhs.constant)),
+                    _ => Expr::Binary(*op, number(lhs.number()?), number(rhs.number()?))
+                        .eval(&none, 0)
+                        .map(Linear::constant),
+                }
+            }
+            _ => self.eval(&none, 0).map(Linear::constant),
+        }
+    }
+
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<i64>, pc: i64) -> Result<i64, ExprError> {
+        match self {
+            Expr::Number(value) => Ok(*value),
+            Expr::Symbol(name) => lookup(name).ok_or_else(|| ExprError::Undefined(name.clone())),
+            Expr::Current => Ok(pc),
+            Expr::Anonymous(_) => Err(ExprError::Invalid(
+                "anonymous labels cannot be used here".to_string(),
+            )),
+            Expr::Unary(op, inner) => {
+                let value = inner.eval(lookup, pc)?;
+                Ok(match op {
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::Not => !value,
+                    UnaryOp::Lo => value & 0xFF,
+                    UnaryOp::Hi => (value >> 8) & 0xFF,
+                    UnaryOp::LogicalNot => (value == 0) as i64,
+                })
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(lookup, pc)?;
+                let rhs = rhs.eval(lookup, pc)?;
+                match op {
+                    BinaryOp::Add => Ok(lhs.wrapping_add(rhs)),
+                    BinaryOp::Sub => Ok(lhs.wrapping_sub(rhs)),
+                    BinaryOp::Mul => Ok(lhs.wrapping_mul(rhs)),
+                    BinaryOp::Div | BinaryOp::Mod if rhs == 0 => {
+                        Err(ExprError::Invalid("division by zero".to_string()))
+                    }
+                    BinaryOp::Div => Ok(lhs.wrapping_div(rhs)),
+                    BinaryOp::Mod => Ok(lhs.wrapping_rem(rhs)),
+                    BinaryOp::And => Ok(lhs & rhs),
+                    BinaryOp::Or => Ok(lhs | rhs),
+                    BinaryOp::Xor => Ok(lhs ^ rhs),
+                    BinaryOp::Shl | BinaryOp::Shr if !(0..64).contains(&rhs) => {
+                        Err(ExprError::Invalid(format!("invalid shift amount {}", rhs)))
+                    }
+                    BinaryOp::Shl => Ok(lhs << rhs),
+                    BinaryOp::Shr => Ok(lhs >> rhs),
+                    BinaryOp::Eq => Ok((lhs == rhs) as i64),
+                    BinaryOp::Ne => Ok((lhs != rhs) as i64),
+                    BinaryOp::Lt => Ok((lhs < rhs) as i64),
+                    BinaryOp::Le => Ok((lhs <= rhs) as i64),
+                    BinaryOp::Gt => Ok((lhs > rhs) as i64),
+                    BinaryOp::Ge => Ok((lhs >= rhs) as i64),
+                    BinaryOp::LogicalAnd => Ok((lhs != 0 && rhs != 0) as i64),
+                    BinaryOp::LogicalOr => Ok((lhs != 0 || rhs != 0) as i64),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(value: i64) -> Box<Expr> {
+        Box::new(Expr::Number(value))
+    }
+
+    #[test]
+    fn test_eval() {
+        let lookup = |name: &str| (name == "base").then_some(0x8000);
+        let expr = Expr::Binary(
+            BinaryOp::Add,
+            Box::new(Expr::Symbol("base".to_string())),
+            Box::new(Expr::Binary(BinaryOp::Mul, num(2), num(3))),
+        );
+        assert_eq!(expr.eval(&lookup, 0), Ok(0x8006));
+        assert_eq!(
+            Expr::Unary(UnaryOp::Hi, Box::new(Expr::Current)).eval(&lookup, 0x1234),
+            Ok(0x12)
+        );
+        assert_eq!(
+            Expr::Unary(UnaryOp::Lo, Box::new(Expr::Current)).eval(&lookup, 0x1234),
+            Ok(0x34)
+        );
+        assert_eq!(Expr::Unary(UnaryOp::Not, num(0)).eval(&lookup, 0), Ok(-1));
+        assert_eq!(
+            Expr::Unary(UnaryOp::LogicalNot, num(2)).eval(&lookup, 0),
+            Ok(0)
+        );
+        assert_eq!(
+            Expr::Binary(
+                BinaryOp::LogicalAnd,
+                Box::new(Expr::Binary(BinaryOp::Ge, num(3), num(3))),
+                Box::new(Expr::Binary(BinaryOp::Ne, num(1), num(2)))
+            )
+            .eval(&lookup, 0),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn test_eval_linear() {
+        let lookup = |name: &str| match name {
+            "tabela" => Some(Linear::base(".data", 4)),
+            "fim" => Some(Linear::base(".data", 10)),
+            "N" => Some(Linear::constant(2)),
+            _ => None,
+        };
+        let symbol = |name: &str| Box::new(Expr::Symbol(name.to_string()));
+        let pc = Linear::base(".text", 6);
+
+        let expr = Expr::Binary(BinaryOp::Add, symbol("tabela"), symbol("N"));
+        assert_eq!(expr.eval_linear(&lookup, &pc), Ok(Linear::base(".data", 6)));
+        let expr = Expr::Binary(BinaryOp::Sub, symbol("fim"), symbol("tabela"));
+        assert_eq!(expr.eval_linear(&lookup, &pc), Ok(Linear::constant(6)));
+        let expr = Expr::Binary(BinaryOp::Sub, Box::new(Expr::Current), num(2));
+        assert_eq!(expr.eval_linear(&lookup, &pc), Ok(Linear::base(".text", 4)));
+        let expr = Expr::Binary(BinaryOp::Mul, symbol("tabela"), num(2));
+        assert_eq!(
+            expr.eval_linear(&lookup, &pc),
+            Ok(Linear {
+                constant: 8,
+                terms: vec![(".data", 2)]
+            })
+        );
+        let expr = Expr::Unary(UnaryOp::Hi, symbol("tabela"));
+        assert_eq!(
+            expr.eval_linear(&lookup, &pc),
+            Err(ExprError::Invalid(
+                "expression cannot be relocated".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        let lookup = |_: &str| None;
+        assert_eq!(
+            Expr::Symbol("fim".to_string()).eval(&lookup, 0),
+            Err(ExprError::Undefined("fim".to_string()))
+        );
+        assert_eq!(
+            Expr::Binary(BinaryOp::Mod, num(1), num(0)).eval(&lookup, 0),
+            Err(ExprError::Invalid("division by zero".to_string()))
+        );
+        assert_eq!(
+            Expr::Binary(BinaryOp::Shl, num(1), num(64)).eval(&lookup, 0),
+            Err(ExprError::Invalid("invalid shift amount 64".to_string()))
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+// Where the assembler reads the files named by `.include` and `.incbin`, and
+// the one given to `assemble_file`.
+pub trait FileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    // The key used to tell whether two paths name the same file.
+    fn canonical(&self, path: &Path) -> PathBuf {
+        path.to_path_buf()
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+pub struct Disk;
+
+impl FileSystem for Disk {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn canonical(&self, path: &Path) -> PathBuf {
+        path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+    }
+}
+
+// Files kept in memory. The first one added is the program given to
+// `assemble`; the others can only be reached through `.include` and `.incbin`.
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl Sources {
+    pub fn new() -> Self {
+        Sources { files: Vec::new() }
+    }
+
+    pub fn add(mut self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) -> Self {
+        let path = normalize(path.as_ref());
+        let contents = contents.into();
+        match self.files.iter_mut().find(|(file, _)| *file == path) {
+            Some((_, file)) => *file = contents,
+            None => self.files.push((path, contents)),
+        }
+        self
+    }
+
+    pub fn main(&self) -> Option<&Path> {
+        self.files.first().map(|(path, _)| path.as_path())
+    }
+
+    fn get(&self, path: &Path) -> Option<&[u8]> {
+        let path = normalize(path);
+        self.files
+            .iter()
+            .find(|(file, _)| *file == path)
+            .map(|(_, contents)| contents.as_slice())
+    }
+}
+
+impl From<&str> for Sources {
+    fn from(source: &str) -> Self {
+        Sources::new().add("<source>", source)
+    }
+}
+
+impl FileSystem for Sources {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.get(path)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.get(path).is_some()
+    }
+
+    fn canonical(&self, path: &Path) -> PathBuf {
+        normalize(path)
+    }
+}
+
+// Drops `.` and folds `dir/..`, without looking at the disk.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(normal.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                normal.pop();
+            }
+            _ => normal.push(component),
+        }
+    }
+    normal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sources() {
+        let sources = Sources::new()
+            .add("src/main.casm", "hlt")
+            .add("./src/../inc/io.inc", "nop")
+            .add("src/main.casm", "ret");
+        assert_eq!(sources.main(), Some(Path::new("src/main.casm")));
+        assert_eq!(sources.read(Path::new("src/main.casm")).unwrap(), b"ret");
+        assert!(sources.is_file(Path::new("inc/io.inc")));
+        assert!(sources.is_file(Path::new("src/../inc/./io.inc")));
+        assert!(!sources.is_file(Path::new("io.inc")));
+        assert_eq!(
+            sources.read(Path::new("io.inc")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(normalize(Path::new("../a/../../b")), Path::new("../../b"));
+    }
+}
@@ -0,0 +1,238 @@
+use super::expr::Expr;
+use super::lexer::{tokenize, Token};
+use super::parser::{parse_line, Operand, OperandKind, Statement, StatementKind};
+use super::source::directive_line;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Block {
+    If,
+    While,
+    Repeat,
+}
+
+// An open `.ifz`, `.while` or `.repeat`. Its labels are numbered like the ones
+// renamed by macro expansion, so they never clash.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Flow {
+    pub block: Block,
+    pub name: &'static str,
+    pub id: usize,
+}
+
+pub(super) enum Directive {
+    Open(Block, &'static str),
+    Else,
+    EndIf,
+    EndWhile,
+    Until,
+}
+
+impl Block {
+    // The directive that closes the block.
+    pub fn end(self) -> &'static str {
+        match self {
+            Block::If => "endif",
+            Block::While => "endwhile",
+            Block::Repeat => "until",
+        }
+    }
+}
+
+pub(super) fn directive(text: &str) -> Option<Directive> {
+    let directives = [
+        ("ifz", Directive::Open(Block::If, "ifz")),
+        ("ifnz", Directive::Open(Block::If, "ifnz")),
+        ("ifn", Directive::Open(Block::If, "ifn")),
+        ("ifnn", Directive::Open(Block::If, "ifnn")),
+        ("while", Directive::Open(Block::While, "while")),
+        ("repeat", Directive::Open(Block::Repeat, "repeat")),
+        ("else", Directive::Else),
+        ("endif", Directive::EndIf),
+        ("endwhile", Directive::EndWhile),
+        ("until", Directive::Until),
+    ];
+    directives
+        .into_iter()
+        .find(|(name, _)| directive_line(text, name).is_some())
+        .map(|(_, directive)| directive)
+}
+
+// A flow directive after a label, as in `laco: .while R0 != 10`: the column of
+// the label and the line with the label blanked out, so the block still opens
+// and closes.
+pub(super) fn labeled(text: &str) -> Option<(usize, String)> {
+    let (label, rest) = code(text).split_once(':')?;
+    let name = label.trim();
+    if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || matches!(c, '_' | '.'))
+        || directive(rest).is_none()
+    {
+        return None;
+    }
+    let blank = " ".repeat(label.chars().count() + 1);
+    Some((indent(label) + 1, blank + &text[label.len() + 1..]))
+}
+
+// `.ifz R1` skips to `.else` or `.endif` unless R1 is zero; `.while R0 != 10`
+// leaves the loop once the comparison is false.
+pub(super) fn open(text: &str, flow: &Flow) -> Result<Vec<Statement>, (usize, String)> {
+    let column = indent(text) + 1;
+    let id = flow.id;
+    Ok(match flow.block {
+        Block::If => {
+            let jump = match flow.name {
+                "ifz" => "JNZ",
+                "ifnz" => "JZ",
+                "ifn" => "JNN",
+                _ => "JN",
+            };
+            let (compare, _) = compare(text, flow.name, false)?;
+            vec![compare, jump_to(jump, label("else", id), column)]
+        }
+        Block::While => {
+            let (mut compare, jump) = compare(text, flow.name, true)?;
+            compare.label = Some(label("while", id));
+            vec![compare, jump_to(jump, label("endwhile", id), column)]
+        }
+        Block::Repeat => {
+            if let Some((offset, rest)) = directive_line(text, "repeat") {
+                if !code(rest).trim().is_empty() {
+                    return Err((offset + 1, "expected end of line".to_string()));
+                }
+            }
+            vec![mark(label("repeat", id), column)]
+        }
+    })
+}
+
+pub(super) fn otherwise(text: &str, flow: &Flow) -> Vec<Statement> {
+    let column = indent(text) + 1;
+    vec![
+        jump_to("JMP", label("endif", flow.id), column),
+        mark(label("else", flow.id), column),
+    ]
+}
+
+pub(super) fn close(
+    text: &str,
+    flow: &Flow,
+    has_else: bool,
+) -> Result<Vec<Statement>, (usize, String)> {
+    let column = indent(text) + 1;
+    let id = flow.id;
+    Ok(match flow.block {
+        Block::If if has_else => vec![mark(label("endif", id), column)],
+        Block::If => vec![mark(label("else", id), column)],
+        Block::While => vec![
+            jump_to("JMP", label("while", id), column),
+            mark(label("endwhile", id), column),
+        ],
+        // Loops back while the condition is still false.
+        Block::Repeat => {
+            let (compare, jump) = compare(text, "until", true)?;
+            vec![compare, jump_to(jump, label("repeat", id), column)]
+        }
+    })
+}
+
+// The line as a CMP of the two sides of its comparison, keeping the columns of
+// the operands for error messages, and the jump taken when the comparison is
+// false. Without `operator`, the register is compared with 0. `<` and `>=`
+// compare unsigned values: CMP sets Overflow when the subtraction borrows.
+fn compare(
+    text: &str,
+    directive: &str,
+    operator: bool,
+) -> Result<(Statement, &'static str), (usize, String)> {
+    let (offset, rest) = directive_line(text, directive).expect("checked by directive()");
+    let rest = code(rest);
+    let start = offset - directive.chars().count() - 1;
+    let mut line: Vec<char> = text.chars().take(offset + rest.chars().count()).collect();
+    line.splice(
+        start..offset,
+        "CMP"
+            .chars()
+            .chain(std::iter::repeat_n(' ', offset - start - 3)),
+    );
+
+    let mut jump = "";
+    if operator {
+        let tokens = tokenize(rest).map_err(|(column, message)| (column + offset, message))?;
+        let mut depth = 0;
+        let found = tokens.iter().find_map(|spanned| {
+            match spanned.token {
+                Token::LParen => depth += 1,
+                Token::RParen => depth -= 1,
+                _ if depth > 0 => {}
+                Token::EqEq => return Some((spanned.column, 2, "JNZ")),
+                Token::NotEq => return Some((spanned.column, 2, "JZ")),
+                Token::Less => return Some((spanned.column, 1, "JNO")),
+                Token::GreaterEq => return Some((spanned.column, 2, "JO")),
+                Token::Greater | Token::LessEq => return Some((spanned.column, 0, "")),
+                _ => {}
+            }
+            None
+        });
+        let Some((column, len @ 1..=2, false_jump)) = found else {
+            let column = found.map_or(line.len() + 1, |(column, ..)| column + offset);
+            return Err((
+                column,
+                "expected a comparison with ==, !=, < or >=".to_string(),
+            ));
+        };
+        let at = column - 1 + offset;
+        line.splice(
+            at..at + len,
+            ",".chars().chain(std::iter::repeat_n(' ', len - 1)),
+        );
+        jump = false_jump;
+    } else {
+        line.extend(", 0".chars());
+    }
+
+    let line: String = line.into_iter().collect();
+    let mut statement = parse_line(&line)?;
+    if let StatementKind::Instruction { operands, .. } = &mut statement.kind {
+        // The 0 added after the register points back at the register.
+        if let (false, [first, zero]) = (operator, &mut operands[..]) {
+            zero.column = first.column;
+        }
+    }
+    Ok((statement, jump))
+}
+
+fn label(kind: &str, id: usize) -> String {
+    format!("{}@{}", kind, id)
+}
+
+fn mark(label: String, column: usize) -> Statement {
+    Statement {
+        label: Some(label),
+        kind: StatementKind::Empty,
+        column,
+    }
+}
+
+fn jump_to(mnemonic: &str, target: String, column: usize) -> Statement {
+    Statement {
+        label: None,
+        kind: StatementKind::Instruction {
+            mnemonic: mnemonic.to_string(),
+            operands: vec![Operand {
+                kind: OperandKind::Expr(Expr::Symbol(target)),
+                column,
+            }],
+        },
+        column,
+    }
+}
+
+// The text before a `;` comment.
+fn code(text: &str) -> &str {
+    text.split(';').next().unwrap_or_default()
+}
+
+fn indent(text: &str) -> usize {
+    text.chars().take_while(|c| c.is_whitespace()).count()
+}
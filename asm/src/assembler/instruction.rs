@@ -0,0 +1,528 @@
+use crate::isa::{JumpMode, Opcode};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mnemonic {
+    pub opcode: Opcode,
+    pub byte: bool,
+    pub condition: Option<JumpMode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arg {
+    Register(u8),
+    Indirect(u8),
+    Literal(i64),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct EncodeError {
+    pub operand: Option<usize>,
+    pub message: String,
+}
+
+impl EncodeError {
+    fn new(operand: Option<usize>, message: impl Into<String>) -> Self {
+        EncodeError {
+            operand,
+            message: message.into(),
+        }
+    }
+}
+
+fn opcode(name: &str) -> Option<Opcode> {
+    let opcode = match name {
+        "NOP" => Opcode::NOP,
+        "HLT" => Opcode::HLT,
+        "MOV" => Opcode::MOV,
+        "PHR" => Opcode::PHR,
+        "PLR" => Opcode::PLR,
+        "ADD" => Opcode::ADD,
+        "SUB" => Opcode::SUB,
+        "MUL" => Opcode::MUL,
+        "DIV" => Opcode::DIV,
+        "MOD" => Opcode::MOD,
+        "INC" => Opcode::INC,
+        "DEC" => Opcode::DEC,
+        "AND" => Opcode::AND,
+        "OR" => Opcode::OR,
+        "XOR" => Opcode::XOR,
+        "NOT" => Opcode::NOT,
+        "SHL" => Opcode::SHL,
+        "SHR" => Opcode::SHR,
+        "CMP" => Opcode::CMP,
+        "JMP" => Opcode::JMP,
+        "JPC" => Opcode::JPC,
+        "JSB" => Opcode::JSB,
+        "RSB" => Opcode::RSB,
+        "CLI" => Opcode::CLI,
+        "SEI" => Opcode::SEI,
+        "RSI" => Opcode::RSI,
+        _ => return None,
+    };
+    Some(opcode)
+}
+
+fn condition(name: &str) -> Option<JumpMode> {
+    let condition = match name {
+        "JZ" => JumpMode::Zero,
+        "JNZ" => JumpMode::NotZero,
+        "JN" => JumpMode::Negative,
+        "JNN" => JumpMode::NotNegative,
+        "JO" => JumpMode::Overflow,
+        "JNO" => JumpMode::NotOverflow,
+        _ => return None,
+    };
+    Some(condition)
+}
+
+fn has_byte_form(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::MOV
+            | Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::MOD
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::NOT
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::CMP
+    )
+}
+
+pub fn lookup(name: &str) -> Option<Mnemonic> {
+    let name = name.to_uppercase();
+    if let Some(opcode) = opcode(&name) {
+        return Some(Mnemonic {
+            opcode,
+            byte: false,
+            condition: None,
+        });
+    }
+    if let Some(condition) = condition(&name) {
+        return Some(Mnemonic {
+            opcode: Opcode::JPC,
+            byte: false,
+            condition: Some(condition),
+        });
+    }
+    let opcode = opcode(name.strip_suffix('B')?)?;
+    has_byte_form(opcode).then_some(Mnemonic {
+        opcode,
+        byte: true,
+        condition: None,
+    })
+}
+
+fn first_byte(mnemonic: Mnemonic, mode: u8) -> u8 {
+    ((mnemonic.opcode as u8) << 3) | ((mnemonic.byte as u8) << 2) | mode
+}
+
+fn word(index: usize, value: i64) -> Result<[u8; 2], EncodeError> {
+    if !(-0x8000..=0xFFFF).contains(&value) {
+        return Err(EncodeError::new(
+            Some(index),
+            format!("value {} does not fit in 16 bits", value),
+        ));
+    }
+    Ok((value as u16).to_le_bytes())
+}
+
+fn byte(index: usize, value: i64) -> Result<u8, EncodeError> {
+    if !(-0x80..=0xFF).contains(&value) {
+        return Err(EncodeError::new(
+            Some(index),
+            format!("value {} does not fit in 8 bits", value),
+        ));
+    }
+    Ok(value as u8)
+}
+
+fn expect_count(args: &[Arg], count: usize) -> Result<(), EncodeError> {
+    if args.len() != count {
+        return Err(EncodeError::new(
+            None,
+            format!("expected {} operand(s), found {}", count, args.len()),
+        ));
+    }
+    Ok(())
+}
+
+// Operand kinds in the notation of the instruction table in machine.md.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Reg,
+    RegPtr,
+    Lit,
+    Mode,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Reg => "Reg",
+            Kind::RegPtr => "Reg*",
+            Kind::Lit => "Lit",
+            Kind::Mode => "Mode",
+        }
+    }
+}
+
+// Every operand combination the CPU implements, per machine.md.
+fn forms(mnemonic: Mnemonic) -> &'static [&'static [Kind]] {
+    use Kind::*;
+    match (mnemonic.opcode, mnemonic.byte) {
+        (Opcode::NOP | Opcode::HLT | Opcode::RSB | Opcode::CLI | Opcode::SEI | Opcode::RSI, _) => {
+            &[&[]]
+        }
+        (Opcode::PHR | Opcode::PLR | Opcode::INC | Opcode::DEC | Opcode::NOT, _) => &[&[Reg]],
+        (Opcode::JMP | Opcode::JSB, _) => &[&[RegPtr], &[Lit]],
+        (Opcode::JPC, _) if mnemonic.condition.is_some() => &[&[Reg], &[Lit]],
+        (Opcode::JPC, _) => &[&[Mode, Reg], &[Mode, Lit]],
+        (Opcode::MOV, false) => &[&[Reg, Reg], &[Reg, Lit], &[RegPtr, Reg], &[Reg, RegPtr]],
+        (Opcode::MOV, true) => &[&[Reg, Reg], &[Reg, Lit], &[RegPtr, Reg]],
+        _ => &[&[Reg, Reg], &[Reg, Lit]],
+    }
+}
+
+fn name(mnemonic: Mnemonic) -> String {
+    match mnemonic.condition {
+        Some(condition) => condition.mnemonic().to_string(),
+        None => format!(
+            "{:?}{}",
+            mnemonic.opcode,
+            if mnemonic.byte { "B" } else { "" }
+        ),
+    }
+}
+
+fn check_form(mnemonic: Mnemonic, args: &[Arg]) -> Result<(), EncodeError> {
+    let forms = forms(mnemonic);
+    expect_count(args, forms[0].len())?;
+    let kinds: Vec<Kind> = args
+        .iter()
+        .zip(forms[0])
+        .map(|(arg, expected)| match arg {
+            Arg::Register(_) => Kind::Reg,
+            Arg::Indirect(_) => Kind::RegPtr,
+            Arg::Literal(_) if *expected == Kind::Mode => Kind::Mode,
+            Arg::Literal(_) => Kind::Lit,
+        })
+        .collect();
+
+    // Point at the first operand that no legal form allows after the ones before it.
+    for idx in 0..kinds.len() {
+        if !forms.iter().any(|form| form[..=idx] == kinds[..=idx]) {
+            let name = name(mnemonic);
+            let legal: Vec<String> = forms
+                .iter()
+                .map(|form| {
+                    let kinds: Vec<&str> = form.iter().map(|kind| kind.name()).collect();
+                    format!("{} {}", name, kinds.join(", "))
+                })
+                .collect();
+            return Err(EncodeError::new(
+                Some(idx),
+                format!(
+                    "{} does not accept {} here; valid forms: {}",
+                    name,
+                    kinds[idx].name(),
+                    legal.join(" | ")
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn encode(mnemonic: Mnemonic, args: &[Arg]) -> Result<Vec<u8>, EncodeError> {
+    check_form(mnemonic, args)?;
+    match mnemonic.opcode {
+        Opcode::NOP | Opcode::HLT | Opcode::RSB | Opcode::CLI | Opcode::SEI | Opcode::RSI => {
+            Ok(vec![first_byte(mnemonic, 0)])
+        }
+        Opcode::PHR | Opcode::PLR | Opcode::INC | Opcode::DEC | Opcode::NOT => match args[0] {
+            Arg::Register(reg) => Ok(vec![first_byte(mnemonic, 0), reg]),
+            _ => unreachable!(),
+        },
+        Opcode::JMP | Opcode::JSB => match args[0] {
+            Arg::Indirect(reg) => Ok(vec![first_byte(mnemonic, 0), reg]),
+            Arg::Literal(value) => {
+                let [lo, hi] = word(0, value)?;
+                Ok(vec![first_byte(mnemonic, 1), lo, hi])
+            }
+            _ => unreachable!(),
+        },
+        Opcode::JPC => {
+            // JZ, JNZ, ... carry the jump mode in the mnemonic and only take the target.
+            let (mode, target) = match (mnemonic.condition, args[0]) {
+                (Some(condition), _) => (condition as u8, 0),
+                (None, Arg::Literal(value))
+                    if (0..=0xFF).contains(&value)
+                        && JumpMode::from(value as u8) != JumpMode::None =>
+                {
+                    (value as u8, 1)
+                }
+                (None, _) => {
+                    return Err(EncodeError::new(
+                        Some(0),
+                        "expected a jump mode between 0 and 5",
+                    ))
+                }
+            };
+            match args[target] {
+                Arg::Register(reg) => Ok(vec![first_byte(mnemonic, 0), (mode << 4) | reg]),
+                Arg::Literal(value) => {
+                    let [lo, hi] = word(target, value)?;
+                    Ok(vec![first_byte(mnemonic, 1), mode, lo, hi])
+                }
+                Arg::Indirect(_) => unreachable!(),
+            }
+        }
+        _ => match (args[0], args[1]) {
+            (Arg::Register(dest), Arg::Register(orig)) => {
+                Ok(vec![first_byte(mnemonic, 0), (dest << 4) | orig])
+            }
+            (Arg::Register(dest), Arg::Literal(value)) => {
+                let mut bytes = vec![first_byte(mnemonic, 1), dest];
+                if mnemonic.byte {
+                    bytes.push(byte(1, value)?);
+                } else {
+                    bytes.extend(word(1, value)?);
+                }
+                Ok(bytes)
+            }
+            (Arg::Indirect(dest), Arg::Register(orig)) => {
+                Ok(vec![first_byte(mnemonic, 2), (dest << 4) | orig])
+            }
+            (Arg::Register(dest), Arg::Indirect(orig)) => {
+                Ok(vec![first_byte(mnemonic, 3), (dest << 4) | orig])
+            }
+            _ => unreachable!(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_str(name: &str, args: &[Arg]) -> Vec<u8> {
+        encode(lookup(name).unwrap(), args).unwrap()
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(
+            lookup("movb"),
+            Some(Mnemonic {
+                opcode: Opcode::MOV,
+                byte: true,
+                condition: None,
+            })
+        );
+        assert_eq!(
+            lookup("JSB"),
+            Some(Mnemonic {
+                opcode: Opcode::JSB,
+                byte: false,
+                condition: None,
+            })
+        );
+        assert_eq!(
+            lookup("jnz"),
+            Some(Mnemonic {
+                opcode: Opcode::JPC,
+                byte: false,
+                condition: Some(JumpMode::NotZero),
+            })
+        );
+        assert_eq!(lookup("PHRB"), None);
+        assert_eq!(lookup("JC"), None);
+        assert_eq!(lookup("FOO"), None);
+    }
+
+    #[test]
+    fn test_encode_table() {
+        use Arg::*;
+        assert_eq!(encode_str("NOP", &[]), [0b0000_0000]);
+        assert_eq!(encode_str("HLT", &[]), [0b0000_1000]);
+        assert_eq!(
+            encode_str("MOV", &[Register(1), Register(2)]),
+            [0b0001_0000, 0x12]
+        );
+        assert_eq!(
+            encode_str("MOV", &[Register(0), Literal(-1)]),
+            [0b0001_0001, 0, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            encode_str("MOV", &[Indirect(0), Register(1)]),
+            [0b0001_0010, 0x01]
+        );
+        assert_eq!(
+            encode_str("MOV", &[Register(2), Indirect(0)]),
+            [0b0001_0011, 0x20]
+        );
+        assert_eq!(
+            encode_str("MOVB", &[Register(1), Literal(10)]),
+            [0b0001_0101, 1, 10]
+        );
+        assert_eq!(
+            encode_str("MOVB", &[Indirect(0), Register(1)]),
+            [0b0001_0110, 0x01]
+        );
+        assert_eq!(encode_str("PHR", &[Register(3)]), [0b0001_1000, 3]);
+        assert_eq!(encode_str("PLR", &[Register(1)]), [0b0010_0000, 1]);
+        assert_eq!(
+            encode_str("ADDB", &[Register(1), Register(0)]),
+            [0b0010_1100, 0x10]
+        );
+        assert_eq!(
+            encode_str("SHR", &[Register(0), Literal(0x1234)]),
+            [0b1000_1001, 0, 0x34, 0x12]
+        );
+        assert_eq!(encode_str("INCB", &[Register(4)]), [0b0101_0100, 4]);
+        assert_eq!(encode_str("NOT", &[Register(0)]), [0b0111_1000, 0]);
+        assert_eq!(
+            encode_str("CMPB", &[Register(1), Literal(7)]),
+            [0b1001_0101, 1, 7]
+        );
+        assert_eq!(
+            encode_str("JMP", &[Literal(0x100)]),
+            [0b1001_1001, 0x00, 0x01]
+        );
+        assert_eq!(encode_str("JMP", &[Indirect(3)]), [0b1001_1000, 3]);
+        assert_eq!(
+            encode_str("JPC", &[Literal(1), Register(2)]),
+            [0b1010_0000, 0x12]
+        );
+        assert_eq!(
+            encode_str("JPC", &[Literal(0), Literal(0x200)]),
+            [0b1010_0001, 0, 0x00, 0x02]
+        );
+        assert_eq!(
+            encode_str("JSB", &[Literal(0x300)]),
+            [0b1010_1001, 0x00, 0x03]
+        );
+        assert_eq!(encode_str("RSB", &[]), [0b1011_0000]);
+        assert_eq!(encode_str("CLI", &[]), [0b1011_1000]);
+        assert_eq!(encode_str("SEI", &[]), [0b1100_0000]);
+        assert_eq!(encode_str("RSI", &[]), [0b1100_1000]);
+    }
+
+    #[test]
+    fn test_encode_conditional_jumps() {
+        use Arg::*;
+        assert_eq!(
+            encode_str("JZ", &[Literal(0x200)]),
+            [0b1010_0001, 0, 0x00, 0x02]
+        );
+        assert_eq!(encode_str("JNZ", &[Register(3)]), [0b1010_0000, 0x13]);
+        assert_eq!(
+            encode_str("JN", &[Literal(0x10)]),
+            [0b1010_0001, 2, 0x10, 0x00]
+        );
+        assert_eq!(encode_str("JNN", &[Register(0)]), [0b1010_0000, 0x30]);
+        assert_eq!(
+            encode_str("JO", &[Literal(0)]),
+            [0b1010_0001, 4, 0x00, 0x00]
+        );
+        assert_eq!(encode_str("JNO", &[Register(15)]), [0b1010_0000, 0x5F]);
+        assert_eq!(
+            encode(lookup("JZ").unwrap(), &[Literal(0), Register(1)]),
+            Err(EncodeError::new(None, "expected 1 operand(s), found 2"))
+        );
+        assert_eq!(
+            encode(lookup("JO").unwrap(), &[Indirect(1)]),
+            Err(EncodeError::new(
+                Some(0),
+                "JO does not accept Reg* here; valid forms: JO Reg | JO Lit"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        use Arg::*;
+        let mov = lookup("MOV").unwrap();
+        assert_eq!(
+            encode(mov, &[Register(0)]),
+            Err(EncodeError::new(None, "expected 2 operand(s), found 1"))
+        );
+        assert_eq!(
+            encode(mov, &[Literal(1), Register(0)]),
+            Err(EncodeError::new(
+                Some(0),
+                "MOV does not accept Lit here; valid forms: MOV Reg, Reg | MOV Reg, Lit | MOV Reg*, Reg | MOV Reg, Reg*"
+            ))
+        );
+        assert_eq!(
+            encode(mov, &[Register(0), Literal(0x10000)]),
+            Err(EncodeError::new(
+                Some(1),
+                "value 65536 does not fit in 16 bits"
+            ))
+        );
+        assert_eq!(
+            encode(lookup("ADDB").unwrap(), &[Register(0), Literal(256)]),
+            Err(EncodeError::new(
+                Some(1),
+                "value 256 does not fit in 8 bits"
+            ))
+        );
+        assert_eq!(
+            encode(lookup("JPC").unwrap(), &[Literal(6), Register(0)]),
+            Err(EncodeError::new(
+                Some(0),
+                "expected a jump mode between 0 and 5"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_encode_rejects_illegal_modes() {
+        use Arg::*;
+        let error = |name, args: &[Arg]| encode(lookup(name).unwrap(), args).unwrap_err();
+        assert_eq!(
+            error("ADD", &[Indirect(1), Register(2)]),
+            EncodeError::new(
+                Some(0),
+                "ADD does not accept Reg* here; valid forms: ADD Reg, Reg | ADD Reg, Lit"
+            )
+        );
+        assert_eq!(
+            error("MOVB", &[Register(1), Indirect(2)]),
+            EncodeError::new(
+                Some(1),
+                "MOVB does not accept Reg* here; valid forms: MOVB Reg, Reg | MOVB Reg, Lit | MOVB Reg*, Reg"
+            )
+        );
+        assert_eq!(
+            error("JSB", &[Register(3)]),
+            EncodeError::new(
+                Some(0),
+                "JSB does not accept Reg here; valid forms: JSB Reg* | JSB Lit"
+            )
+        );
+        assert_eq!(
+            error("JPC", &[Register(1), Literal(0)]),
+            EncodeError::new(
+                Some(0),
+                "JPC does not accept Reg here; valid forms: JPC Mode, Reg | JPC Mode, Lit"
+            )
+        );
+        assert_eq!(
+            error("PHR", &[Literal(1)]),
+            EncodeError::new(
+                Some(0),
+                "PHR does not accept Lit here; valid forms: PHR Reg"
+            )
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use super::expr::Expr;
+use super::parser::{OperandKind, StatementKind};
+use super::source::Line;
+use super::Diagnostic;
+
+// Qualifies local labels (`.loop`) with the global label before them and numbers
+// anonymous labels (`:`), so the passes only ever see plain symbols. Labels made
+// unique by macro expansion and `.struct`/`.enum` members do not open a new local
+// scope.
+pub(super) fn scope(lines: &mut [Line]) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+    let total = lines
+        .iter()
+        .filter(|line| line.statement.label.as_deref() == Some(":"))
+        .count();
+    let mut scope = String::new();
+    let mut seen = 0;
+
+    for line in lines.iter_mut() {
+        if let Some(label) = &mut line.statement.label {
+            if label == ":" {
+                seen += 1;
+                *label = anonymous(seen);
+            } else if label.starts_with('.') {
+                // Macro expansion already made its own local labels unique.
+                if scope.is_empty() && !label.contains('@') {
+                    errors.push(Diagnostic {
+                        excerpt: Some(line.text.clone()),
+                        ..line.file.error(
+                            line.number,
+                            1,
+                            format!("local label '{}' before any global label", label),
+                        )
+                    });
+                }
+                *label = format!("{}{}", scope, label);
+            } else if !label.contains(['@', '.']) {
+                scope = label.clone();
+            }
+        }
+
+        let operands = match &mut line.statement.kind {
+            StatementKind::Instruction { operands, .. } => operands,
+            StatementKind::Directive { args, .. } => args,
+            StatementKind::Empty => continue,
+        };
+        for operand in operands {
+            if let OperandKind::Expr(expr) = &mut operand.kind {
+                if let Err(message) = qualify(expr, &scope, seen, total) {
+                    errors.push(Diagnostic {
+                        excerpt: Some(line.text.clone()),
+                        ..line.file.error(line.number, operand.column, message)
+                    });
+                }
+            }
+        }
+    }
+    errors
+}
+
+fn anonymous(index: usize) -> String {
+    format!(":{}", index)
+}
+
+fn qualify(expr: &mut Expr, scope: &str, seen: usize, total: usize) -> Result<(), String> {
+    match expr {
+        Expr::Symbol(name) if name.starts_with('.') => *name = format!("{}{}", scope, name),
+        Expr::Anonymous(offset) => {
+            // `:-` is the nearest label at or before this line, `:+` the next one.
+            let target = seen as i64 + if *offset > 0 { *offset } else { *offset + 1 };
+            if target < 1 || target > total as i64 {
+                let sign = if *offset > 0 { "+" } else { "-" };
+                return Err(format!(
+                    "no anonymous label for ':{}'",
+                    sign.repeat(offset.unsigned_abs() as usize)
+                ));
+            }
+            *expr = Expr::Symbol(anonymous(target as usize));
+        }
+        Expr::Unary(_, inner) => qualify(inner, scope, seen, total)?,
+        Expr::Binary(_, lhs, rhs) => {
+            qualify(lhs, scope, seen, total)?;
+            qualify(rhs, scope, seen, total)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,408 @@
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Ident(String),
+    // Name after a '.', as written: a directive or a local label.
+    Directive(String),
+    Number(i64),
+    Str(String),
+    Comma,
+    Colon,
+    Star,
+    Minus,
+    Plus,
+    Slash,
+    Percent,
+    Amp,
+    Pipe,
+    Caret,
+    Tilde,
+    Shl,
+    Shr,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    EqEq,
+    NotEq,
+    AmpAmp,
+    PipePipe,
+    Bang,
+    LParen,
+    RParen,
+    Dollar,
+    // `:+`, `:--`, ... referencing the nth anonymous label after or before.
+    Anon(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned {
+    pub token: Token,
+    pub column: usize,
+}
+
+// One character of a string or character literal, with `\n`, `\t`, `\r`, `\0`,
+// `\\`, `\"`, `\'` and `\xHH` escapes.
+fn character(chars: &[char], i: &mut usize) -> Result<char, (usize, String)> {
+    let column = *i + 1;
+    let c = chars[*i];
+    *i += 1;
+    if c != '\\' {
+        return Ok(c);
+    }
+    let Some(&escape) = chars.get(*i) else {
+        return Err((column, "unterminated escape sequence".to_string()));
+    };
+    *i += 1;
+    match escape {
+        'n' => Ok('\n'),
+        't' => Ok('\t'),
+        'r' => Ok('\r'),
+        '0' => Ok('\0'),
+        '\\' | '"' | '\'' => Ok(escape),
+        'x' => {
+            let digits: String = chars.iter().skip(*i).take(2).collect();
+            match u8::from_str_radix(&digits, 16) {
+                Ok(value) if digits.len() == 2 && digits.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    *i += 2;
+                    Ok(value as char)
+                }
+                _ => Err((column, "expected two hex digits after '\\x'".to_string())),
+            }
+        }
+        _ => Err((column, format!("unknown escape sequence '\\{}'", escape))),
+    }
+}
+
+pub fn tokenize(line: &str) -> Result<Vec<Spanned>, (usize, String)> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == ';' {
+            break;
+        }
+
+        let token = match c {
+            '<' | '>' | '=' | '!' | '&' | '|' => {
+                let (token, len) = match (c, chars.get(i + 1)) {
+                    ('<', Some('<')) => (Token::Shl, 2),
+                    ('<', Some('=')) => (Token::LessEq, 2),
+                    ('<', _) => (Token::Less, 1),
+                    ('>', Some('>')) => (Token::Shr, 2),
+                    ('>', Some('=')) => (Token::GreaterEq, 2),
+                    ('>', _) => (Token::Greater, 1),
+                    ('=', Some('=')) => (Token::EqEq, 2),
+                    ('!', Some('=')) => (Token::NotEq, 2),
+                    ('!', _) => (Token::Bang, 1),
+                    ('&', Some('&')) => (Token::AmpAmp, 2),
+                    ('&', _) => (Token::Amp, 1),
+                    ('|', Some('|')) => (Token::PipePipe, 2),
+                    ('|', _) => (Token::Pipe, 1),
+                    _ => return Err((column, format!("unexpected character '{}'", c))),
+                };
+                i += len;
+                token
+            }
+            ':' if matches!(chars.get(i + 1), Some('+' | '-')) => {
+                let sign = chars[i + 1];
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && chars[i] == sign {
+                    i += 1;
+                }
+                let count = (i - start) as i64;
+                Token::Anon(if sign == '+' { count } else { -count })
+            }
+            c if punct(c).is_some() => {
+                i += 1;
+                punct(c).unwrap()
+            }
+            '"' => {
+                i += 1;
+                let mut text = String::new();
+                while i < chars.len() && chars[i] != '"' {
+                    text.push(character(&chars, &mut i)?);
+                }
+                if i == chars.len() {
+                    return Err((column, "unterminated string".to_string()));
+                }
+                i += 1;
+                Token::Str(text)
+            }
+            '\'' => {
+                i += 1;
+                let value = match chars.get(i) {
+                    Some('\'') | None => None,
+                    Some(_) => Some(character(&chars, &mut i)?),
+                };
+                match (value, chars.get(i)) {
+                    (Some(value), Some('\'')) => {
+                        i += 1;
+                        Token::Number(value as i64)
+                    }
+                    _ => return Err((column, "invalid character literal".to_string())),
+                }
+            }
+            '.' => {
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+                if i == start {
+                    return Err((column, "expected directive name after '.'".to_string()));
+                }
+                Token::Directive(chars[start..i].iter().collect())
+            }
+            c if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                Token::Number(
+                    parse_number(&text).ok_or((column, format!("invalid number '{}'", text)))?,
+                )
+            }
+            c if is_ident_start(c) => {
+                // `rotina.laco` names a local label from outside its scope.
+                let start = i;
+                while i < chars.len()
+                    && (is_ident_char(chars[i])
+                        || chars[i] == '.' && chars.get(i + 1).is_some_and(|&c| is_ident_start(c)))
+                {
+                    i += 1;
+                }
+                Token::Ident(chars[start..i].iter().collect())
+            }
+            _ => return Err((column, format!("unexpected character '{}'", c))),
+        };
+
+        tokens.push(Spanned { token, column });
+    }
+
+    Ok(tokens)
+}
+
+fn punct(c: char) -> Option<Token> {
+    let token = match c {
+        ',' => Token::Comma,
+        ':' => Token::Colon,
+        '*' => Token::Star,
+        '-' => Token::Minus,
+        '+' => Token::Plus,
+        '/' => Token::Slash,
+        '%' => Token::Percent,
+        '^' => Token::Caret,
+        '~' => Token::Tilde,
+        '(' => Token::LParen,
+        ')' => Token::RParen,
+        '$' => Token::Dollar,
+        _ => return None,
+    };
+    Some(token)
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+// `@` only appears in labels renamed by macro expansion.
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '@'
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    let lower = text.to_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: &str) -> Vec<Token> {
+        tokenize(line)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.token)
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize_instruction() {
+        assert_eq!(
+            tokens("loop: MOV r1*, R0 ; comment"),
+            vec![
+                Token::Ident("loop".to_string()),
+                Token::Colon,
+                Token::Ident("MOV".to_string()),
+                Token::Ident("r1".to_string()),
+                Token::Star,
+                Token::Comma,
+                Token::Ident("R0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_numbers() {
+        assert_eq!(
+            tokens("42 0x7B 0b0101 0xFF_00 'A'"),
+            vec![
+                Token::Number(42),
+                Token::Number(0x7B),
+                Token::Number(0b0101),
+                Token::Number(0xFF00),
+                Token::Number(65),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_directive_and_string() {
+        assert_eq!(
+            tokens("nome: .ASCII \"João; ok\""),
+            vec![
+                Token::Ident("nome".to_string()),
+                Token::Colon,
+                Token::Directive("ASCII".to_string()),
+                Token::Str("João; ok".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_escapes() {
+        assert_eq!(
+            tokens(r#"'\n' '\'' "a\"b\\;\x41\0""#),
+            vec![
+                Token::Number(10),
+                Token::Number(39),
+                Token::Str("a\"b\\;A\0".to_string()),
+            ]
+        );
+        assert_eq!(
+            tokenize(r#""\x4G""#),
+            Err((2, "expected two hex digits after '\\x'".to_string()))
+        );
+        assert_eq!(
+            tokenize("''"),
+            Err((1, "invalid character literal".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_tokenize_comparisons() {
+        assert_eq!(
+            tokens("a<=b<c>=d>e==f!=!g&&h||i"),
+            vec![
+                Token::Ident("a".to_string()),
+                Token::LessEq,
+                Token::Ident("b".to_string()),
+                Token::Less,
+                Token::Ident("c".to_string()),
+                Token::GreaterEq,
+                Token::Ident("d".to_string()),
+                Token::Greater,
+                Token::Ident("e".to_string()),
+                Token::EqEq,
+                Token::Ident("f".to_string()),
+                Token::NotEq,
+                Token::Bang,
+                Token::Ident("g".to_string()),
+                Token::AmpAmp,
+                Token::Ident("h".to_string()),
+                Token::PipePipe,
+                Token::Ident("i".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_labels() {
+        assert_eq!(
+            tokens(".loop: JNZ :- ; :+"),
+            vec![
+                Token::Directive("loop".to_string()),
+                Token::Colon,
+                Token::Ident("JNZ".to_string()),
+                Token::Anon(-1),
+            ]
+        );
+        assert_eq!(
+            tokens(": JMP :++ + 1, rotina.fim"),
+            vec![
+                Token::Colon,
+                Token::Ident("JMP".to_string()),
+                Token::Anon(2),
+                Token::Plus,
+                Token::Number(1),
+                Token::Comma,
+                Token::Ident("rotina.fim".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_operators() {
+        assert_eq!(
+            tokens("(a+$)*2/3%4&5|6^~7<<1>>2"),
+            vec![
+                Token::LParen,
+                Token::Ident("a".to_string()),
+                Token::Plus,
+                Token::Dollar,
+                Token::RParen,
+                Token::Star,
+                Token::Number(2),
+                Token::Slash,
+                Token::Number(3),
+                Token::Percent,
+                Token::Number(4),
+                Token::Amp,
+                Token::Number(5),
+                Token::Pipe,
+                Token::Number(6),
+                Token::Caret,
+                Token::Tilde,
+                Token::Number(7),
+                Token::Shl,
+                Token::Number(1),
+                Token::Shr,
+                Token::Number(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        assert_eq!(
+            tokenize("MOV R0, 0xZZ"),
+            Err((9, "invalid number '0xZZ'".to_string()))
+        );
+        assert_eq!(
+            tokenize("MOV R0, 1 = 2"),
+            Err((11, "unexpected character '='".to_string()))
+        );
+        assert_eq!(
+            tokenize(".ascii \"open"),
+            Err((8, "unterminated string".to_string()))
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use super::parser::{OperandKind, StatementKind};
+use super::source::{Line, Origin};
+use super::Assembler;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const BYTES_PER_ROW: usize = 4;
+
+impl Assembler {
+    // Address, bytes and source text of every line from the last successful assembly,
+    // followed by the symbol table and a cross-reference of where each symbol is used.
+    pub fn listing(&self) -> String {
+        let mut out = String::new();
+        let mut file = None;
+        for (line, record) in self.lines.iter().zip(&self.records) {
+            if file != Some(&line.file.name) {
+                file = Some(&line.file.name);
+                writeln!(out, "; {}", line.file.name).unwrap();
+            }
+
+            let address = match (&line.statement.label, &line.statement.kind) {
+                (Some(label), StatementKind::Directive { name, .. }) if name == "const" => {
+                    format!("={}", hex_value(self.symbols[label]))
+                }
+                (None, StatementKind::Empty) => String::new(),
+                _ => format!("{:04X}", record.address),
+            };
+            let marker = match line.file.origin {
+                Some((_, Origin::Macro(..))) => '+',
+                _ => ' ',
+            };
+            let mut rows = record.bytes.chunks(BYTES_PER_ROW);
+            let row = format!(
+                "{:<5}  {:<11}  {:>5}{} {}",
+                address,
+                hex_bytes(rows.next().unwrap_or_default()),
+                line.number,
+                marker,
+                line.text.trim()
+            );
+            writeln!(out, "{}", row.trim_end()).unwrap();
+
+            let mut address = record.address as usize + BYTES_PER_ROW;
+            for row in rows {
+                writeln!(out, "{:04X}   {}", address, hex_bytes(row)).unwrap();
+                address += row.len();
+            }
+        }
+
+        let mut defined = BTreeMap::new();
+        let mut used: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for line in &self.lines {
+            if let Some(label) = &line.statement.label {
+                defined.insert(label.as_str(), position(line));
+            }
+            let operands = match &line.statement.kind {
+                StatementKind::Instruction { operands, .. } => operands,
+                StatementKind::Directive { args, .. } => args,
+                StatementKind::Empty => continue,
+            };
+            let mut symbols = Vec::new();
+            for operand in operands {
+                if let OperandKind::Expr(expr) = &operand.kind {
+                    expr.symbols(&mut symbols);
+                }
+            }
+            for name in symbols {
+                used.entry(name).or_default().push(position(line));
+            }
+        }
+
+        // Anonymous labels have no name worth listing.
+        let mut names: Vec<&str> = self
+            .symbols
+            .keys()
+            .map(String::as_str)
+            .filter(|name| !name.starts_with(':'))
+            .collect();
+        names.sort_unstable();
+
+        writeln!(out, "\n; symbols").unwrap();
+        for name in &names {
+            writeln!(
+                out,
+                "{:<24} {:<6} {}",
+                name,
+                hex_value(self.symbols[*name]),
+                defined.get(name).map_or("-D", String::as_str)
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\n; cross-reference").unwrap();
+        for name in &names {
+            let uses = used
+                .get(name)
+                .map_or("-".to_string(), |uses| uses.join(" "));
+            writeln!(out, "{:<24} {}", name, uses).unwrap();
+        }
+        out
+    }
+}
+
+fn position(line: &Line) -> String {
+    format!("{}:{}", line.file.name, line.number)
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn hex_value(value: i64) -> String {
+    if (0..=0xFFFF).contains(&value) {
+        format!("{:04X}", value)
+    } else {
+        value.to_string()
+    }
+}
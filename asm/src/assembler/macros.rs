@@ -0,0 +1,322 @@
+use super::lexer::{tokenize, Token};
+use super::source::SourceFile;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Parameter names with their optional default argument text.
+pub(super) type Params = Vec<(String, Option<String>)>;
+
+pub(super) struct Macro {
+    pub name: String,
+    pub params: Params,
+    pub body: Vec<(usize, String)>,
+    pub file: Rc<SourceFile>,
+}
+
+impl Macro {
+    // Labels defined in the body get a per-expansion suffix so that expanding the
+    // same macro twice never produces duplicate symbols.
+    pub fn expand(
+        &self,
+        args: &[(usize, String)],
+        id: usize,
+    ) -> Result<Vec<(usize, String)>, String> {
+        if args.len() > self.params.len() {
+            return Err(format!(
+                "macro '{}' takes {} argument(s), found {}",
+                self.name,
+                self.params.len(),
+                args.len()
+            ));
+        }
+
+        let mut replacements = HashMap::new();
+        for (_, text) in &self.body {
+            if let Ok(tokens) = tokenize(text) {
+                if let [first, second, ..] = tokens.as_slice() {
+                    match (&first.token, &second.token) {
+                        (Token::Ident(label), Token::Colon) => {
+                            replacements.insert(label.clone(), format!("{}@{}", label, id));
+                        }
+                        (Token::Directive(label), Token::Colon) => {
+                            replacements.insert(format!(".{}", label), format!("{}@{}", label, id));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        for (idx, (param, default)) in self.params.iter().enumerate() {
+            let value = match args.get(idx) {
+                Some((_, arg)) if !arg.is_empty() => arg.clone(),
+                _ => default.clone().ok_or_else(|| {
+                    format!("missing argument '{}' for macro '{}'", param, self.name)
+                })?,
+            };
+            replacements.insert(param.clone(), value);
+        }
+
+        Ok(self
+            .body
+            .iter()
+            .map(|(number, text)| (*number, substitute(text, &replacements)))
+            .collect())
+    }
+}
+
+pub(super) fn parse_params(text: &str, offset: usize) -> Result<Params, (usize, String)> {
+    let mut params: Params = Vec::new();
+    for (column, arg) in split_args(text, offset) {
+        let (name, default) = match arg.split_once('=') {
+            Some((name, default)) => (name.trim(), Some(default.trim().to_string())),
+            None => (arg.as_str(), None),
+        };
+        if !is_identifier(name) {
+            return Err((column, format!("invalid macro parameter '{}'", arg)));
+        }
+        if params.iter().any(|(param, _)| param == name) {
+            return Err((column, format!("duplicate macro parameter '{}'", name)));
+        }
+        params.push((name.to_string(), default));
+    }
+    Ok(params)
+}
+
+// Splits `text` at top-level commas, keeping parenthesized groups and strings intact.
+// Columns are 1-based and relative to the line `text` was cut from, `offset` chars in.
+pub(super) fn split_args(text: &str, offset: usize) -> Vec<(usize, String)> {
+    let chars: Vec<char> = strip_comment(text).chars().collect();
+    let mut args = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for i in 0..=chars.len() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match chars.get(i) {
+            Some('\\') if in_string => {
+                escaped = true;
+                continue;
+            }
+            Some('"') => {
+                in_string = !in_string;
+                continue;
+            }
+            Some('(') if !in_string => {
+                depth += 1;
+                continue;
+            }
+            Some(')') if !in_string => {
+                depth -= 1;
+                continue;
+            }
+            Some(',') if !in_string && depth == 0 => {}
+            Some(_) => continue,
+            None => {}
+        }
+
+        let raw: String = chars[start..i].iter().collect();
+        let leading = raw.len() - raw.trim_start().len();
+        let column = offset + start + raw[..leading].chars().count() + 1;
+        args.push((column, raw.trim().to_string()));
+        start = i + 1;
+    }
+
+    if args.len() == 1 && args[0].1.is_empty() {
+        args.clear();
+    }
+    args
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (idx, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &text[..idx],
+            _ => {}
+        }
+    }
+    text
+}
+
+pub(super) fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+// Replaces whole identifiers outside strings and comments. Words after a '.'
+// are looked up with the dot, so only local labels match there.
+fn substitute(text: &str, replacements: &HashMap<String, String>) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c == ';' {
+            out.extend(&chars[i..]);
+            break;
+        }
+        if c == '"' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            i = (i + 1).min(chars.len());
+            out.extend(&chars[start..i]);
+            continue;
+        }
+        if c.is_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '@')
+            {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            let key = if start > 0 && chars[start - 1] == '.' {
+                format!(".{}", word)
+            } else {
+                word.clone()
+            };
+            match replacements.get(&key) {
+                Some(replacement) if !c.is_ascii_digit() => out.push_str(replacement),
+                _ => out.push_str(&word),
+            }
+            continue;
+        }
+        out.push(c);
+        i += 1;
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(params: &str, body: &[&str]) -> Macro {
+        Macro {
+            name: "teste".to_string(),
+            params: parse_params(params, 0).unwrap(),
+            body: body
+                .iter()
+                .enumerate()
+                .map(|(idx, text)| (idx + 2, text.to_string()))
+                .collect(),
+            file: SourceFile::root("<source>", None),
+        }
+    }
+
+    fn args(text: &str) -> Vec<(usize, String)> {
+        split_args(text, 0)
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(
+            split_args(" R1, (a, b) , \"x, y\" ; c, d", 10),
+            vec![
+                (12, "R1".to_string()),
+                (16, "(a, b)".to_string()),
+                (25, "\"x, y\"".to_string()),
+            ]
+        );
+        assert_eq!(split_args("   ", 0), vec![]);
+        assert_eq!(
+            split_args("a,,b", 0),
+            vec![
+                (1, "a".to_string()),
+                (3, String::new()),
+                (4, "b".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_params() {
+        assert_eq!(
+            parse_params("reg, value = 1", 0),
+            Ok(vec![
+                ("reg".to_string(), None),
+                ("value".to_string(), Some("1".to_string()))
+            ])
+        );
+        assert_eq!(
+            parse_params("a, 2b", 0),
+            Err((4, "invalid macro parameter '2b'".to_string()))
+        );
+        assert_eq!(
+            parse_params("a, a", 0),
+            Err((4, "duplicate macro parameter 'a'".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_expand_substitutes_arguments() {
+        let body = definition(
+            "reg, value=1",
+            &[
+                "ADD reg, value ; reg += value",
+                ".ascii \"reg\"",
+                ".byte 0x1reg",
+            ],
+        );
+        assert_eq!(
+            body.expand(&args("R3"), 0).unwrap(),
+            vec![
+                (2, "ADD R3, 1 ; reg += value".to_string()),
+                (3, ".ascii \"reg\"".to_string()),
+                (4, ".byte 0x1reg".to_string()),
+            ]
+        );
+        assert_eq!(
+            body.expand(&args("R3, fim - inicio"), 0).unwrap()[0].1,
+            "ADD R3, fim - inicio ; reg += value"
+        );
+    }
+
+    #[test]
+    fn test_expand_renames_labels() {
+        let body = definition(
+            "count",
+            &["loop: DEC count", ".fim: JNZ loop", "JMP .fim ; .fim"],
+        );
+        assert_eq!(
+            body.expand(&args("R0"), 7).unwrap(),
+            vec![
+                (2, "loop@7: DEC R0".to_string()),
+                (3, ".fim@7: JNZ loop@7".to_string()),
+                (4, "JMP .fim@7 ; .fim".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let body = definition("a, b=2", &["NOP"]);
+        assert_eq!(
+            body.expand(&args(""), 0),
+            Err("missing argument 'a' for macro 'teste'".to_string())
+        );
+        assert_eq!(
+            body.expand(&args("1, 2, 3"), 0),
+            Err("macro 'teste' takes 2 argument(s), found 3".to_string())
+        );
+    }
+}
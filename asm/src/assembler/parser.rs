@@ -0,0 +1,529 @@
+use super::expr::{BinaryOp, Expr, UnaryOp};
+use super::lexer::{tokenize, Spanned, Token};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OperandKind {
+    Register(u8),
+    Indirect(u8),
+    Expr(Expr),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operand {
+    pub kind: OperandKind,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementKind {
+    Empty,
+    Instruction {
+        mnemonic: String,
+        operands: Vec<Operand>,
+    },
+    Directive {
+        name: String,
+        args: Vec<Operand>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub label: Option<String>,
+    pub kind: StatementKind,
+    pub column: usize,
+}
+
+pub fn register(name: &str) -> Option<u8> {
+    let upper = name.to_uppercase();
+    match upper.as_str() {
+        "PC" => Some(14),
+        "SP" => Some(15),
+        _ => {
+            let digits = upper.strip_prefix('R')?;
+            if digits.is_empty() || (digits.len() > 1 && digits.starts_with('0')) {
+                return None;
+            }
+            digits.parse().ok().filter(|&r: &u8| r < 16)
+        }
+    }
+}
+
+// Splits off the label that starts a line: `name:`, a local `.name:` or an
+// anonymous `:`. Returns the label and the index of the first token after it.
+pub fn label(tokens: &[Spanned]) -> Result<(Option<String>, usize), (usize, String)> {
+    let first = tokens.first().map(|s| &s.token);
+    let second = tokens.get(1).map(|s| &s.token);
+    match (first, second) {
+        (Some(Token::Ident(name)), Some(Token::Colon)) => {
+            if register(name).is_some() {
+                return Err((1, format!("'{}' is a register and cannot be a label", name)));
+            }
+            Ok((Some(name.clone()), 2))
+        }
+        (Some(Token::Directive(name)), Some(Token::Colon)) => Ok((Some(format!(".{}", name)), 2)),
+        (Some(Token::Colon), _) => Ok((Some(":".to_string()), 1)),
+        _ => Ok((None, 0)),
+    }
+}
+
+pub fn parse_line(line: &str) -> Result<Statement, (usize, String)> {
+    let tokens = tokenize(line)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        end_column: line.chars().count() + 1,
+    };
+    parser.statement()
+}
+
+// A line of an `.enum` block: `NAME` or `NAME = value`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub name: String,
+    pub column: usize,
+    pub value: Option<Expr>,
+}
+
+// Blank lines give None.
+pub fn parse_member(line: &str) -> Result<Option<Member>, (usize, String)> {
+    // '=' on its own is not a token, so the line is split by hand.
+    let code = &line[..line.find(';').unwrap_or(line.len())];
+    let (name, value) = match code.find('=') {
+        Some(idx) => (&line[..idx], Some(&line[idx + 1..])),
+        None => (line, None),
+    };
+
+    let tokens = tokenize(name)?;
+    let (name, column) = match tokens.as_slice() {
+        [] if value.is_none() => return Ok(None),
+        [Spanned {
+            token: Token::Ident(name),
+            column,
+        }] if register(name).is_none() => (name.clone(), *column),
+        [Spanned {
+            token: Token::Ident(name),
+            ..
+        }, extra, ..]
+            if register(name).is_none() =>
+        {
+            return Err((extra.column, "expected '=' or end of line".to_string()))
+        }
+        _ => {
+            let column = tokens.first().map_or(1, |s| s.column);
+            return Err((column, "expected a constant name".to_string()));
+        }
+    };
+
+    let value = match value {
+        Some(text) => {
+            let offset = line.chars().count() - text.chars().count();
+            let mut tokens =
+                tokenize(text).map_err(|(column, message)| (column + offset, message))?;
+            for token in &mut tokens {
+                token.column += offset;
+            }
+            let mut parser = Parser {
+                tokens: &tokens,
+                pos: 0,
+                end_column: line.chars().count() + 1,
+            };
+            let expr = parser.expr()?;
+            if parser.peek().is_some() {
+                return Err((parser.column(), "expected end of line".to_string()));
+            }
+            Some(expr)
+        }
+        None => None,
+    };
+    Ok(Some(Member {
+        name,
+        column,
+        value,
+    }))
+}
+
+struct Parser<'a> {
+    tokens: &'a [Spanned],
+    pos: usize,
+    end_column: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|s| &s.token)
+    }
+
+    fn column(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.end_column, |s| s.column)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|s| s.token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn statement(&mut self) -> Result<Statement, (usize, String)> {
+        let (label, pos) = label(self.tokens)?;
+        self.pos = pos;
+
+        let column = self.column();
+        let kind = match self.next() {
+            None => StatementKind::Empty,
+            Some(Token::Ident(mnemonic)) => StatementKind::Instruction {
+                mnemonic: mnemonic.to_uppercase(),
+                operands: self.operands()?,
+            },
+            Some(Token::Directive(name)) => StatementKind::Directive {
+                name: name.to_lowercase(),
+                args: self.operands()?,
+            },
+            Some(_) => return Err((column, "expected instruction or directive".to_string())),
+        };
+
+        Ok(Statement {
+            label,
+            kind,
+            column,
+        })
+    }
+
+    fn operands(&mut self) -> Result<Vec<Operand>, (usize, String)> {
+        let mut operands = Vec::new();
+        if self.peek().is_none() {
+            return Ok(operands);
+        }
+        loop {
+            operands.push(self.operand()?);
+            match self.next() {
+                None => return Ok(operands),
+                Some(Token::Comma) => continue,
+                Some(_) => {
+                    return Err((self.tokens[self.pos - 1].column, "expected ','".to_string()))
+                }
+            }
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, (usize, String)> {
+        let column = self.column();
+        let kind = match self.peek() {
+            Some(Token::Ident(name)) if register(name).is_some() => {
+                let reg = register(name).unwrap();
+                self.pos += 1;
+                if self.peek() == Some(&Token::Star) {
+                    self.pos += 1;
+                    OperandKind::Indirect(reg)
+                } else {
+                    OperandKind::Register(reg)
+                }
+            }
+            Some(Token::Str(text)) => {
+                let text = text.clone();
+                self.pos += 1;
+                OperandKind::Str(text)
+            }
+            _ => OperandKind::Expr(self.expr()?),
+        };
+        Ok(Operand { kind, column })
+    }
+
+    fn expect(&mut self, token: Token, what: &str) -> Result<(), (usize, String)> {
+        let column = self.column();
+        if self.next() != Some(token) {
+            return Err((column, format!("expected {}", what)));
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Expr, (usize, String)> {
+        self.binary(1)
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, (usize, String)> {
+        let mut lhs = self.unary()?;
+        while let Some((op, precedence)) = self.peek().and_then(binary_op) {
+            if precedence < min_precedence {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(precedence + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, (usize, String)> {
+        match self.peek() {
+            Some(Token::Minus) => {
+                self.pos += 1;
+                Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?)))
+            }
+            Some(Token::Tilde) => {
+                self.pos += 1;
+                Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?)))
+            }
+            Some(Token::Bang) => {
+                self.pos += 1;
+                Ok(Expr::Unary(UnaryOp::LogicalNot, Box::new(self.unary()?)))
+            }
+            Some(Token::Plus) => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, (usize, String)> {
+        let column = self.column();
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Dollar) => Ok(Expr::Current),
+            Some(Token::Directive(name)) => Ok(Expr::Symbol(format!(".{}", name))),
+            Some(Token::Anon(offset)) => Ok(Expr::Anonymous(offset)),
+            Some(Token::LParen) => {
+                let expr = self.expr()?;
+                self.expect(Token::RParen, "')'")?;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) => {
+                let function = match name.to_lowercase().as_str() {
+                    "lo" => Some(UnaryOp::Lo),
+                    "hi" => Some(UnaryOp::Hi),
+                    _ => None,
+                };
+                match function {
+                    Some(op) if self.peek() == Some(&Token::LParen) => {
+                        self.pos += 1;
+                        let expr = self.expr()?;
+                        self.expect(Token::RParen, "')'")?;
+                        Ok(Expr::Unary(op, Box::new(expr)))
+                    }
+                    _ => Ok(Expr::Symbol(name)),
+                }
+            }
+            _ => Err((column, "expected operand".to_string())),
+        }
+    }
+}
+
+fn binary_op(token: &Token) -> Option<(BinaryOp, u8)> {
+    let op = match token {
+        Token::PipePipe => (BinaryOp::LogicalOr, 1),
+        Token::AmpAmp => (BinaryOp::LogicalAnd, 2),
+        Token::Pipe => (BinaryOp::Or, 3),
+        Token::Caret => (BinaryOp::Xor, 4),
+        Token::Amp => (BinaryOp::And, 5),
+        Token::EqEq => (BinaryOp::Eq, 6),
+        Token::NotEq => (BinaryOp::Ne, 6),
+        Token::Less => (BinaryOp::Lt, 7),
+        Token::LessEq => (BinaryOp::Le, 7),
+        Token::Greater => (BinaryOp::Gt, 7),
+        Token::GreaterEq => (BinaryOp::Ge, 7),
+        Token::Shl => (BinaryOp::Shl, 8),
+        Token::Shr => (BinaryOp::Shr, 8),
+        Token::Plus => (BinaryOp::Add, 9),
+        Token::Minus => (BinaryOp::Sub, 9),
+        Token::Star => (BinaryOp::Mul, 10),
+        Token::Slash => (BinaryOp::Div, 10),
+        Token::Percent => (BinaryOp::Mod, 10),
+        _ => return None,
+    };
+    Some(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operand(kind: OperandKind, column: usize) -> Operand {
+        Operand { kind, column }
+    }
+
+    #[test]
+    fn test_register_names() {
+        assert_eq!(register("R0"), Some(0));
+        assert_eq!(register("r13"), Some(13));
+        assert_eq!(register("pc"), Some(14));
+        assert_eq!(register("SP"), Some(15));
+        assert_eq!(register("R16"), None);
+        assert_eq!(register("R01"), None);
+        assert_eq!(register("RET"), None);
+    }
+
+    #[test]
+    fn test_parse_instruction() {
+        let statement = parse_line("inicio: mov r1*, R0").unwrap();
+        assert_eq!(statement.label, Some("inicio".to_string()));
+        assert_eq!(
+            statement.kind,
+            StatementKind::Instruction {
+                mnemonic: "MOV".to_string(),
+                operands: vec![
+                    operand(OperandKind::Indirect(1), 13),
+                    operand(OperandKind::Register(0), 18),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_directive() {
+        let statement = parse_line("valor: .short 0xFF00, -1, fim").unwrap();
+        assert_eq!(
+            statement.kind,
+            StatementKind::Directive {
+                name: "short".to_string(),
+                args: vec![
+                    operand(OperandKind::Expr(Expr::Number(0xFF00)), 15),
+                    operand(
+                        OperandKind::Expr(Expr::Unary(UnaryOp::Neg, Box::new(Expr::Number(1)))),
+                        23
+                    ),
+                    operand(OperandKind::Expr(Expr::Symbol("fim".to_string())), 27),
+                ],
+            }
+        );
+    }
+
+    fn parse_expr(text: &str) -> Expr {
+        match parse_line(&format!(".short {}", text)).unwrap().kind {
+            StatementKind::Directive { mut args, .. } => match args.remove(0).kind {
+                OperandKind::Expr(expr) => expr,
+                kind => panic!("unexpected operand {:?}", kind),
+            },
+            kind => panic!("unexpected statement {:?}", kind),
+        }
+    }
+
+    fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    #[test]
+    fn test_parse_expression_precedence() {
+        let symbol = |name: &str| Expr::Symbol(name.to_string());
+        assert_eq!(
+            parse_expr("buffer + 2*OFFSET"),
+            binary(
+                BinaryOp::Add,
+                symbol("buffer"),
+                binary(BinaryOp::Mul, Expr::Number(2), symbol("OFFSET"))
+            )
+        );
+        assert_eq!(
+            parse_expr("end - start - 1"),
+            binary(
+                BinaryOp::Sub,
+                binary(BinaryOp::Sub, symbol("end"), symbol("start")),
+                Expr::Number(1)
+            )
+        );
+        assert_eq!(
+            parse_expr("1 << 4 | FLAG & ~0x0F"),
+            binary(
+                BinaryOp::Or,
+                binary(BinaryOp::Shl, Expr::Number(1), Expr::Number(4)),
+                binary(
+                    BinaryOp::And,
+                    symbol("FLAG"),
+                    Expr::Unary(UnaryOp::Not, Box::new(Expr::Number(0x0F)))
+                )
+            )
+        );
+        assert_eq!(
+            parse_expr("hi($ + 2)"),
+            Expr::Unary(
+                UnaryOp::Hi,
+                Box::new(binary(BinaryOp::Add, Expr::Current, Expr::Number(2)))
+            )
+        );
+        assert_eq!(parse_expr("lo"), symbol("lo"));
+        assert_eq!(
+            parse_expr("!DEBUG || NIVEL + 1 >= 2 && BASE == 0xF000"),
+            binary(
+                BinaryOp::LogicalOr,
+                Expr::Unary(UnaryOp::LogicalNot, Box::new(symbol("DEBUG"))),
+                binary(
+                    BinaryOp::LogicalAnd,
+                    binary(
+                        BinaryOp::Ge,
+                        binary(BinaryOp::Add, symbol("NIVEL"), Expr::Number(1)),
+                        Expr::Number(2)
+                    ),
+                    binary(BinaryOp::Eq, symbol("BASE"), Expr::Number(0xF000))
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_member() {
+        assert_eq!(parse_member("  ; nada"), Ok(None));
+        assert_eq!(
+            parse_member("  VERDE"),
+            Ok(Some(Member {
+                name: "VERDE".to_string(),
+                column: 3,
+                value: None
+            }))
+        );
+        assert_eq!(
+            parse_member("AZUL = 1 << 2 ; comentário"),
+            Ok(Some(Member {
+                name: "AZUL".to_string(),
+                column: 1,
+                value: Some(Expr::Binary(
+                    BinaryOp::Shl,
+                    Box::new(Expr::Number(1)),
+                    Box::new(Expr::Number(2))
+                ))
+            }))
+        );
+        assert_eq!(
+            parse_member("R1 = 2"),
+            Err((1, "expected a constant name".to_string()))
+        );
+        assert_eq!(
+            parse_member("A B"),
+            Err((3, "expected '=' or end of line".to_string()))
+        );
+        assert_eq!(
+            parse_member("A = 1 2"),
+            Err((7, "expected end of line".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_label_only_and_empty() {
+        let statement = parse_line("loop:   ; nada").unwrap();
+        assert_eq!(statement.label, Some("loop".to_string()));
+        assert_eq!(statement.kind, StatementKind::Empty);
+        assert_eq!(parse_line("").unwrap().kind, StatementKind::Empty);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse_line("MOV R0 R1"),
+            Err((8, "expected ','".to_string()))
+        );
+        assert_eq!(
+            parse_line("MOV R0,"),
+            Err((8, "expected operand".to_string()))
+        );
+        assert_eq!(
+            parse_line("MOV R0, (1 + 2"),
+            Err((15, "expected ')'".to_string()))
+        );
+        assert_eq!(
+            parse_line("r1: NOP"),
+            Err((1, "'r1' is a register and cannot be a label".to_string()))
+        );
+    }
+}
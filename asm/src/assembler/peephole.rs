@@ -0,0 +1,357 @@
+use super::expr::Expr;
+use super::instruction::{lookup, Mnemonic};
+use super::parser::{Operand, OperandKind, StatementKind};
+use super::source::Line;
+use crate::isa::{JumpMode, Opcode};
+use std::collections::HashMap;
+
+// Zero, Negative and Overflow as a bit set; the other flags are never touched.
+const ZERO: u8 = 0b001;
+const NEGATIVE: u8 = 0b010;
+const OVERFLOW: u8 = 0b100;
+const ALL_FLAGS: u8 = ZERO | NEGATIVE | OVERFLOW;
+
+const PC: u8 = 14;
+const SP: u8 = 15;
+
+// An instruction line. A successor of `None` is code the analysis cannot see,
+// such as the caller of a subroutine or a jump through a register.
+struct Node {
+    line: usize,
+    labeled: bool,
+    reads: u8,
+    writes: bool,
+    successors: Vec<Option<usize>>,
+}
+
+// Rewrites instructions into shorter ones that leave registers and memory as
+// they were. Rewrites that change the flags are only made where no JPC can see
+// the difference. Only literals known before any address is assigned are
+// considered, so the layout of the program does not matter.
+pub(super) fn optimize(lines: &mut [Line], defines: &HashMap<String, i64>) {
+    let constants = constants(lines, defines);
+    let nodes = graph(lines, &constants);
+    let live = liveness(&nodes);
+    let live_out = |node: &Node| {
+        node.successors.iter().fold(0, |flags, next| {
+            flags | next.map_or(ALL_FLAGS, |next| live[next])
+        })
+    };
+
+    for node in &nodes {
+        let statement = &mut lines[node.line].statement;
+        if let Some(kind) = rewrite(&statement.kind, live_out(node), &constants) {
+            statement.kind = kind;
+        }
+    }
+
+    // Pairs of instructions that undo each other. The second one must not be a
+    // jump target.
+    for (idx, pair) in nodes.windows(2).enumerate() {
+        let [first, second] = pair else {
+            unreachable!()
+        };
+        if second.labeled || first.successors != [Some(idx + 1)] {
+            continue;
+        }
+        let (Some((a, a_regs)), Some((b, b_regs))) = (
+            registers(&lines[first.line].statement.kind),
+            registers(&lines[second.line].statement.kind),
+        ) else {
+            continue;
+        };
+        match (a.opcode, b.opcode, &a_regs[..], &b_regs[..]) {
+            (Opcode::PHR, Opcode::PLR, [x], [y]) if x == y && *x != SP && *x != PC => {
+                lines[first.line].statement.kind = StatementKind::Empty;
+                lines[second.line].statement.kind = StatementKind::Empty;
+            }
+            (Opcode::MOV, Opcode::MOV, [x, y], [z, w])
+                if !a.byte
+                    && !b.byte
+                    && x == w
+                    && y == z
+                    && ![PC, SP].contains(x)
+                    && ![PC, SP].contains(y) =>
+            {
+                lines[second.line].statement.kind = StatementKind::Empty;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn rewrite(
+    kind: &StatementKind,
+    live_out: u8,
+    constants: &HashMap<String, i64>,
+) -> Option<StatementKind> {
+    let StatementKind::Instruction { mnemonic, operands } = kind else {
+        return None;
+    };
+    let mnemonic = lookup(mnemonic)?;
+    if mnemonic.byte || mnemonic.condition.is_some() {
+        return None;
+    }
+    let [dest, source] = &operands[..] else {
+        return None;
+    };
+    let OperandKind::Register(reg) = dest.kind else {
+        return None;
+    };
+    let value = match &source.kind {
+        OperandKind::Register(other) if mnemonic.opcode == Opcode::MOV && *other == reg => {
+            return Some(StatementKind::Empty);
+        }
+        OperandKind::Expr(expr) => evaluate(expr, constants)?,
+        _ => return None,
+    };
+    if !(-0x8000..=0xFFFF).contains(&value) {
+        return None;
+    }
+
+    let flags_dead = live_out == 0;
+    let itself = Operand {
+        kind: OperandKind::Register(reg),
+        column: source.column,
+    };
+    let one = |name: &str| instruction(name, vec![dest.clone()]);
+    let two = |name: &str, source: &Operand| instruction(name, vec![dest.clone(), source.clone()]);
+    // Each arm leaves the register as the original would; arms without a
+    // condition also leave the flags the same.
+    Some(match (mnemonic.opcode, value as u16) {
+        (Opcode::MOV, 0) if flags_dead => two("XOR", &itself),
+        (Opcode::MOV, 0..=0xFF) => two("MOVB", source),
+        (Opcode::AND | Opcode::MUL, 0) => two("XOR", &itself),
+        (Opcode::CMP, 0) => two("OR", &itself),
+        (Opcode::ADD, 1) => one("INC"),
+        (Opcode::SUB, 1) => one("DEC"),
+        // The carry out of adding 0xFFFF is not the borrow out of subtracting 1.
+        (Opcode::ADD, 0xFFFF) if live_out & OVERFLOW == 0 => one("DEC"),
+        (Opcode::SUB, 0xFFFF) if live_out & OVERFLOW == 0 => one("INC"),
+        (Opcode::AND, 0..=0xFF) if flags_dead => two("ANDB", source),
+        (Opcode::ADD | Opcode::SUB | Opcode::OR | Opcode::XOR | Opcode::SHL | Opcode::SHR, 0)
+        | (Opcode::MUL | Opcode::DIV, 1)
+        | (Opcode::AND, 0xFFFF)
+            if flags_dead =>
+        {
+            StatementKind::Empty
+        }
+        _ => return None,
+    })
+}
+
+fn instruction(mnemonic: &str, operands: Vec<Operand>) -> StatementKind {
+    StatementKind::Instruction {
+        mnemonic: mnemonic.to_string(),
+        operands,
+    }
+}
+
+// An instruction whose operands are all registers.
+fn registers(kind: &StatementKind) -> Option<(Mnemonic, Vec<u8>)> {
+    let StatementKind::Instruction { mnemonic, operands } = kind else {
+        return None;
+    };
+    let registers = operands
+        .iter()
+        .map(|operand| match operand.kind {
+            OperandKind::Register(reg) => Some(reg),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    Some((lookup(mnemonic)?, registers))
+}
+
+// `.const` values and predefined symbols. Labels are left out: their values
+// change as instructions shrink.
+fn constants(lines: &[Line], defines: &HashMap<String, i64>) -> HashMap<String, i64> {
+    let mut values = defines.clone();
+    for line in lines {
+        let (Some(label), StatementKind::Directive { name, args }) =
+            (&line.statement.label, &line.statement.kind)
+        else {
+            continue;
+        };
+        if let (
+            "const",
+            [Operand {
+                kind: OperandKind::Expr(expr),
+                ..
+            }],
+        ) = (name.as_str(), &args[..])
+        {
+            if let Some(value) = evaluate(expr, &values) {
+                values.insert(label.clone(), value);
+            }
+        }
+    }
+    values
+}
+
+fn evaluate(expr: &Expr, constants: &HashMap<String, i64>) -> Option<i64> {
+    if uses_pc(expr) {
+        return None;
+    }
+    expr.eval(&|name| constants.get(name).copied(), 0).ok()
+}
+
+fn uses_pc(expr: &Expr) -> bool {
+    match expr {
+        Expr::Current => true,
+        Expr::Unary(_, inner) => uses_pc(inner),
+        Expr::Binary(_, lhs, rhs) => uses_pc(lhs) || uses_pc(rhs),
+        _ => false,
+    }
+}
+
+// The control flow between instruction lines. Code only falls through to the
+// next instruction when nothing but empty lines and `.const`, `.global` or
+// `.extern` come between them.
+fn graph(lines: &[Line], constants: &HashMap<String, i64>) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    let mut fallthrough: Vec<Option<usize>> = Vec::new();
+    let mut targets: HashMap<&str, Option<usize>> = HashMap::new();
+    let mut pending: Vec<&str> = Vec::new();
+    let mut open = None;
+
+    for (idx, line) in lines.iter().enumerate() {
+        let statement = &line.statement;
+        let mnemonic = match &statement.kind {
+            StatementKind::Empty => {
+                pending.extend(statement.label.as_deref());
+                continue;
+            }
+            StatementKind::Directive { name, .. }
+                if matches!(name.as_str(), "const" | "global" | "extern") =>
+            {
+                continue;
+            }
+            StatementKind::Instruction { mnemonic, .. } => lookup(mnemonic),
+            StatementKind::Directive { .. } => None,
+        };
+        pending.extend(statement.label.as_deref());
+        let Some(mnemonic) = mnemonic else {
+            // Data, `.org` or a section change: whatever runs next is unknown.
+            for label in pending.drain(..) {
+                targets.insert(label, None);
+            }
+            if let Some(node) = open.take() {
+                fallthrough[node] = None;
+            }
+            continue;
+        };
+
+        let node = nodes.len();
+        if let Some(previous) = open.replace(node) {
+            fallthrough[previous] = Some(node);
+        }
+        let labeled = !pending.is_empty();
+        for label in pending.drain(..) {
+            targets.insert(label, Some(node));
+        }
+        nodes.push((idx, mnemonic, labeled));
+        fallthrough.push(None);
+    }
+
+    nodes
+        .into_iter()
+        .enumerate()
+        .map(|(node, (line, mnemonic, labeled))| {
+            let StatementKind::Instruction { operands, .. } = &lines[line].statement.kind else {
+                unreachable!()
+            };
+            let target = |operand: Option<&Operand>| match operand.map(|operand| &operand.kind) {
+                Some(OperandKind::Expr(Expr::Symbol(name))) => {
+                    targets.get(name.as_str()).copied().flatten()
+                }
+                _ => None,
+            };
+            let next = fallthrough[node];
+            let writes_pc = matches!(
+                operands.first(),
+                Some(Operand {
+                    kind: OperandKind::Register(PC),
+                    ..
+                })
+            ) && !matches!(mnemonic.opcode, Opcode::PHR | Opcode::CMP);
+            let successors = match mnemonic.opcode {
+                Opcode::HLT => vec![],
+                Opcode::JMP => vec![target(operands.first())],
+                Opcode::JPC if mnemonic.condition.is_some() => vec![target(operands.first()), next],
+                Opcode::JPC => vec![target(operands.get(1)), next],
+                Opcode::JSB => vec![target(operands.first()), next],
+                Opcode::RSB | Opcode::RSI => vec![None],
+                _ if writes_pc => vec![None],
+                _ => vec![next],
+            };
+            Node {
+                line,
+                labeled,
+                reads: reads(mnemonic, operands, constants),
+                writes: matches!(
+                    mnemonic.opcode,
+                    Opcode::ADD
+                        | Opcode::SUB
+                        | Opcode::MUL
+                        | Opcode::DIV
+                        | Opcode::MOD
+                        | Opcode::INC
+                        | Opcode::DEC
+                        | Opcode::AND
+                        | Opcode::OR
+                        | Opcode::XOR
+                        | Opcode::NOT
+                        | Opcode::SHL
+                        | Opcode::SHR
+                        | Opcode::CMP
+                ),
+                successors,
+            }
+        })
+        .collect()
+}
+
+// The flags a conditional jump tests.
+fn reads(mnemonic: Mnemonic, operands: &[Operand], constants: &HashMap<String, i64>) -> u8 {
+    if mnemonic.opcode != Opcode::JPC {
+        return 0;
+    }
+    let mode = match mnemonic.condition {
+        Some(condition) => condition,
+        None => match operands.first().map(|operand| &operand.kind) {
+            Some(OperandKind::Expr(expr)) => match evaluate(expr, constants) {
+                Some(mode @ 0..=5) => JumpMode::from(mode as u8),
+                _ => return ALL_FLAGS,
+            },
+            _ => return ALL_FLAGS,
+        },
+    };
+    match mode {
+        JumpMode::Zero | JumpMode::NotZero => ZERO,
+        JumpMode::Negative | JumpMode::NotNegative => NEGATIVE,
+        JumpMode::Overflow | JumpMode::NotOverflow => OVERFLOW,
+        JumpMode::None => ALL_FLAGS,
+    }
+}
+
+// The flags that may still be tested when each node starts, solved backwards
+// until nothing changes.
+fn liveness(nodes: &[Node]) -> Vec<u8> {
+    let mut live = vec![0; nodes.len()];
+    loop {
+        let mut changed = false;
+        for (idx, node) in nodes.iter().enumerate().rev() {
+            let out = node.successors.iter().fold(0, |flags, next| {
+                flags | next.map_or(ALL_FLAGS, |next| live[next])
+            });
+            let value = node.reads | if node.writes { 0 } else { out };
+            if value != live[idx] {
+                live[idx] = value;
+                changed = true;
+            }
+        }
+        if !changed {
+            return live;
+        }
+    }
+}
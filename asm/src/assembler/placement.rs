@@ -0,0 +1,86 @@
+use super::parser::StatementKind;
+use super::source::{Line, Location};
+use super::{Assembler, Diagnostic};
+use crate::memory_map::{DEVICE_BASE, ROM_BASE, ROM_END, ROM_SIZE, STACK_BASE};
+
+// A run of bytes written one after the other, started by `.org` or by the
+// beginning of the program.
+pub(super) struct Block {
+    start: u32,
+    end: u32,
+    location: Location,
+}
+
+impl Assembler {
+    // Checks that the bytes about to be written at `self.pc..end` end up in the
+    // ROM image, which is all `Memory::load_rom` can load, and that no two
+    // blocks of the program overlap.
+    pub(super) fn place(&mut self, line: &Line, end: u32) -> Result<(), Diagnostic> {
+        let start = self.pc;
+        let rom_end = ROM_BASE as u32 + ROM_SIZE as u32;
+        if start >= rom_end {
+            let message = match line.statement.kind {
+                StatementKind::Instruction { .. } => format!(
+                    "code at 0x{:04X} is outside ROM (0x{:04X}-0x{:04X}), in {}",
+                    start,
+                    ROM_BASE,
+                    ROM_END,
+                    region(start)
+                ),
+                _ => format!(
+                    "initialized data at 0x{:04X} is in {}, which is not loaded from the ROM image; \
+                     name the address with '.const' instead",
+                    start,
+                    region(start)
+                ),
+            };
+            return Err(line.error(1, message));
+        }
+        if end > rom_end {
+            return Err(line.error(1, format!("program exceeds the {} bytes of ROM", ROM_SIZE)));
+        }
+
+        let contiguous = self.blocks.last().is_some_and(|block| block.end == start);
+        let previous = if contiguous {
+            &self.blocks[..self.blocks.len() - 1]
+        } else {
+            &self.blocks[..]
+        };
+        if let Some(block) = previous
+            .iter()
+            .find(|block| start < block.end && block.start < end)
+        {
+            return Err(line.error(
+                1,
+                format!(
+                    "0x{:04X}-0x{:04X} overlaps the bytes placed at 0x{:04X}-0x{:04X} by {}",
+                    start,
+                    end - 1,
+                    block.start,
+                    block.end - 1,
+                    block.location
+                ),
+            ));
+        }
+
+        match self.blocks.last_mut() {
+            Some(block) if contiguous => block.end = end,
+            _ => self.blocks.push(Block {
+                start,
+                end,
+                location: line.file.location(line.number, 1),
+            }),
+        }
+        Ok(())
+    }
+}
+
+fn region(address: u32) -> &'static str {
+    if address >= DEVICE_BASE as u32 {
+        "Devices"
+    } else if address >= STACK_BASE as u32 {
+        "STACK"
+    } else {
+        "RAM"
+    }
+}
@@ -0,0 +1,279 @@
+use super::expr::{Expr, ExprError, Linear, UnaryOp};
+use super::parser::{Operand, OperandKind};
+use super::source::Line;
+use super::{Assembler, Diagnostic};
+use crate::object::{section_kind, Object, Part, Relocation, Section, Symbol, Target};
+
+pub(super) struct SectionState {
+    pub name: String,
+    pub pc: u32,
+    pub align: usize,
+    pub data: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+}
+
+// A field whose value is only known after linking: `part` of `target + addend`.
+pub(super) type Fixup = (Part, Target, i64);
+
+impl Assembler {
+    pub(super) fn switch_section(&mut self, name: &str) {
+        if let Some(section) = self.section {
+            self.sections[section].pc = self.pc;
+        }
+        let section = match self.sections.iter().position(|s| s.name == name) {
+            Some(section) => section,
+            None => {
+                self.sections.push(SectionState {
+                    name: name.to_string(),
+                    pc: 0,
+                    align: 1,
+                    data: Vec::new(),
+                    relocations: Vec::new(),
+                });
+                self.sections.len() - 1
+            }
+        };
+        self.section = Some(section);
+        self.pc = self.sections[section].pc;
+    }
+
+    // `.text`, `.rodata`, `.data`, `.bss`, `.section <name>` and `.extern`.
+    pub(super) fn section_directive(
+        &mut self,
+        line: &Line,
+        name: &str,
+        args: &[Operand],
+    ) -> Result<(), Diagnostic> {
+        if !self.object {
+            return Err(line.error(
+                line.statement.column,
+                format!("'.{}' is only available when assembling an object", name),
+            ));
+        }
+
+        if name == "extern" {
+            for arg in args {
+                let symbol = symbol_arg(line, arg)?;
+                if !self.emit {
+                    self.check_unique(line, symbol)?;
+                    self.imports.push(symbol.to_string());
+                }
+            }
+            return Ok(());
+        }
+
+        let section = if name == "section" {
+            let arg = super::single_arg(line, &line.statement, args)?;
+            let section = format!(".{}", symbol_arg(line, arg)?);
+            if section_kind(&section).is_none() {
+                return Err(line.error(
+                    arg.column,
+                    format!(
+                        "unknown section '{}'; expected .text, .rodata, .data or .bss",
+                        section
+                    ),
+                ));
+            }
+            section
+        } else {
+            if !args.is_empty() {
+                return Err(line.error(args[0].column, "unexpected argument"));
+            }
+            format!(".{}", name)
+        };
+        self.switch_section(&section);
+        Ok(())
+    }
+
+    pub(super) fn export(
+        &mut self,
+        idx: usize,
+        line: &Line,
+        args: &[Operand],
+    ) -> Result<(), Diagnostic> {
+        for arg in args {
+            let symbol = symbol_arg(line, arg)?;
+            if !self.emit && self.object {
+                self.exports.push((symbol.to_string(), idx, arg.column));
+            }
+        }
+        Ok(())
+    }
+
+    // Evaluates `expr` with labels standing for offsets into their sections.
+    pub(super) fn linear(
+        &self,
+        expr: &Expr,
+        pc: u32,
+        section: Option<usize>,
+    ) -> Result<Linear<Target>, ExprError> {
+        let lookup = |name: &str| {
+            if let Some(&section) = self.symbol_sections.get(name) {
+                let target = Target::Section(self.sections[section].name.clone());
+                Some(Linear::base(target, self.symbols[name]))
+            } else if let Some(&value) = self.symbols.get(name) {
+                Some(Linear::constant(value))
+            } else if self.imports.iter().any(|import| import == name) {
+                Some(Linear::base(Target::Symbol(name.to_string()), 0))
+            } else {
+                None
+            }
+        };
+        let pc = match section {
+            Some(section) => Linear::base(
+                Target::Section(self.sections[section].name.clone()),
+                pc as i64,
+            ),
+            None => Linear::constant(pc as i64),
+        };
+        expr.eval_linear(&lookup, &pc)
+    }
+
+    // Value of a `.const`, with the section it is relative to, if any.
+    pub(super) fn settle(
+        &self,
+        expr: &Expr,
+        pc: u32,
+        section: Option<usize>,
+    ) -> Result<(i64, Option<usize>), ExprError> {
+        if !self.object {
+            return expr
+                .eval(&|name| self.symbols.get(name).copied(), pc as i64)
+                .map(|value| (value, None));
+        }
+        let linear = self.linear(expr, pc, section)?;
+        match &linear.terms[..] {
+            [] => Ok((linear.constant, None)),
+            [(Target::Section(name), 1)] => Ok((
+                linear.constant,
+                self.sections.iter().position(|s| s.name == *name),
+            )),
+            _ => Err(ExprError::Invalid(
+                "expression cannot be relocated".to_string(),
+            )),
+        }
+    }
+
+    // An operand that may need a relocation. Outside the final pass of an object
+    // this is just its value.
+    pub(super) fn field(
+        &self,
+        line: &Line,
+        operand: &Operand,
+    ) -> Result<(i64, Option<Fixup>), Diagnostic> {
+        let OperandKind::Expr(expr) = &operand.kind else {
+            return Err(line.error(operand.column, "expected a numeric value"));
+        };
+        if !self.object || !self.emit {
+            return Ok((self.value(line, operand, false)?, None));
+        }
+
+        let (part, inner) = match expr {
+            Expr::Unary(UnaryOp::Lo, inner) => (Part::Lo, inner.as_ref()),
+            Expr::Unary(UnaryOp::Hi, inner) => (Part::Hi, inner.as_ref()),
+            _ => (Part::Full, expr),
+        };
+        let linear = self
+            .linear(inner, self.statement_pc, self.section)
+            .map_err(|err| match err {
+                ExprError::Undefined(name) => {
+                    line.error(operand.column, format!("undefined symbol '{}'", name))
+                }
+                ExprError::Invalid(message) => line.error(operand.column, message),
+            })?;
+        match &linear.terms[..] {
+            [] => Ok((self.value(line, operand, false)?, None)),
+            [(target, 1)] => Ok((0, Some((part, target.clone(), linear.constant)))),
+            _ => Err(line.error(operand.column, "expression cannot be relocated")),
+        }
+    }
+
+    // Records a relocation for the `size` bytes just written.
+    pub(super) fn relocate(
+        &mut self,
+        line: &Line,
+        column: usize,
+        size: u8,
+        (part, target, addend): Fixup,
+    ) -> Result<(), Diagnostic> {
+        if size == 1 && part == Part::Full {
+            return Err(line.error(
+                column,
+                "relocatable address does not fit in 8 bits; use lo() or hi()",
+            ));
+        }
+        let section = self.section.expect("relocations only happen in objects");
+        let state = &mut self.sections[section];
+        if section_kind(&state.name) == Some(".bss") {
+            return Err(line.error(column, "only zero bytes can be placed in .bss"));
+        }
+        state.relocations.push(Relocation {
+            offset: self.pc as usize - size as usize,
+            size,
+            part,
+            target,
+            addend,
+        });
+        Ok(())
+    }
+
+    pub(super) fn in_bss(&self) -> bool {
+        self.section
+            .is_some_and(|section| section_kind(&self.sections[section].name) == Some(".bss"))
+    }
+
+    pub(super) fn build_object(&self) -> Result<Object, Vec<Diagnostic>> {
+        let mut object = Object::default();
+        for (idx, state) in self.sections.iter().enumerate() {
+            if state.pc == 0 && !self.symbol_sections.values().any(|&s| s == idx) {
+                continue;
+            }
+            let mut data = Vec::new();
+            if section_kind(&state.name) != Some(".bss") {
+                data = state.data.clone();
+                data.resize(state.pc as usize, 0);
+            }
+            object.sections.push(Section {
+                name: state.name.clone(),
+                size: state.pc as usize,
+                align: state.align,
+                data,
+                relocations: state.relocations.clone(),
+            });
+        }
+
+        let mut errors = Vec::new();
+        for (name, idx, column) in &self.exports {
+            let line = &self.lines[*idx];
+            if self.imports.contains(name) {
+                errors
+                    .push(line.error(*column, format!("cannot export external symbol '{}'", name)));
+                continue;
+            }
+            let Some(&value) = self.symbols.get(name) else {
+                errors.push(line.error(*column, format!("undefined symbol '{}'", name)));
+                continue;
+            };
+            object.exports.push(Symbol {
+                name: name.clone(),
+                section: self
+                    .symbol_sections
+                    .get(name)
+                    .map(|&section| self.sections[section].name.clone()),
+                value,
+            });
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        object.imports = self.imports.clone();
+        Ok(object)
+    }
+}
+
+fn symbol_arg<'a>(line: &Line, arg: &'a Operand) -> Result<&'a str, Diagnostic> {
+    match &arg.kind {
+        OperandKind::Expr(Expr::Symbol(name)) => Ok(name),
+        _ => Err(line.error(arg.column, "expected a symbol name")),
+    }
+}
@@ -0,0 +1,959 @@
+use super::expr::{Expr, ExprError};
+use super::files::FileSystem;
+use super::flow::{self, Block, Directive, Flow};
+use super::instruction::lookup;
+use super::labels;
+use super::lexer::{tokenize, Spanned, Token};
+use super::macros::{is_identifier, parse_params, split_args, Macro};
+use super::parser::{
+    label, parse_line, parse_member, Operand, OperandKind, Statement, StatementKind,
+};
+use super::{Diagnostic, Severity};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            return write!(f, "{}", self.file);
+        }
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+// Where the lines of a file or macro expansion were pulled in from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    Include(Location),
+    Macro(String, Location),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Include(location) => write!(f, "included from {}", location),
+            Origin::Macro(name, location) => {
+                write!(f, "in expansion of macro '{}' at {}", name, location)
+            }
+        }
+    }
+}
+
+pub(super) struct SourceFile {
+    pub name: String,
+    pub path: Option<PathBuf>,
+    pub origin: Option<(Rc<SourceFile>, Origin)>,
+}
+
+impl SourceFile {
+    pub fn root(name: &str, path: Option<PathBuf>) -> Rc<Self> {
+        Rc::new(SourceFile {
+            name: name.to_string(),
+            path,
+            origin: None,
+        })
+    }
+
+    pub fn location(&self, line: usize, column: usize) -> Location {
+        Location {
+            file: self.name.clone(),
+            line,
+            column,
+        }
+    }
+
+    pub fn error(&self, line: usize, column: usize, message: impl Into<String>) -> Diagnostic {
+        let mut backtrace = Vec::new();
+        let mut parent = &self.origin;
+        while let Some((file, origin)) = parent {
+            backtrace.push(origin.clone());
+            parent = &file.origin;
+        }
+
+        Diagnostic {
+            severity: Severity::Error,
+            location: self.location(line, column),
+            backtrace,
+            message: message.into(),
+            excerpt: None,
+        }
+    }
+}
+
+pub(super) struct Line {
+    pub file: Rc<SourceFile>,
+    pub number: usize,
+    pub text: String,
+    pub statement: Statement,
+}
+
+impl Line {
+    pub fn error(&self, column: usize, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            excerpt: Some(self.text.clone()),
+            ..self.file.error(self.number, column, message)
+        }
+    }
+
+    pub fn warning(&self, column: usize, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..self.error(column, message)
+        }
+    }
+}
+
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+// An open `.if` block. `active` tells whether the current branch is assembled.
+// Structured blocks such as `.ifz` and `.while` share the stack, so `.else` and
+// `.endif` always close the innermost block; for them `active` is the enclosing
+// state.
+struct Conditional {
+    line: usize,
+    column: usize,
+    active: bool,
+    has_else: bool,
+    flow: Option<Flow>,
+}
+
+pub(super) struct Loader<'a> {
+    include_dirs: &'a [PathBuf],
+    files: &'a dyn FileSystem,
+    active: Vec<(PathBuf, String)>,
+    macros: HashMap<String, Rc<Macro>>,
+    expansions: usize,
+    depth: usize,
+    // Symbols seen so far, with the value of those that are constants, for `.if`.
+    symbols: HashMap<String, Option<i64>>,
+    lines: Vec<Line>,
+    errors: Vec<Diagnostic>,
+}
+
+impl<'a> Loader<'a> {
+    pub fn new(
+        include_dirs: &'a [PathBuf],
+        files: &'a dyn FileSystem,
+        defines: &HashMap<String, i64>,
+    ) -> Self {
+        Loader {
+            include_dirs,
+            files,
+            active: Vec::new(),
+            macros: HashMap::new(),
+            expansions: 0,
+            depth: 0,
+            symbols: defines
+                .iter()
+                .map(|(name, value)| (name.clone(), Some(*value)))
+                .collect(),
+            lines: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn load(mut self, file: Rc<SourceFile>, text: &str) -> Result<Vec<Line>, Vec<Diagnostic>> {
+        if let Some(path) = &file.path {
+            self.active
+                .push((self.files.canonical(path), file.name.clone()));
+        }
+        self.load_file(file, text);
+        self.errors.extend(labels::scope(&mut self.lines));
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        Ok(self.lines)
+    }
+
+    fn load_file(&mut self, file: Rc<SourceFile>, text: &str) {
+        let lines: Vec<(usize, String)> = text
+            .lines()
+            .enumerate()
+            .map(|(idx, text)| (idx + 1, text.to_string()))
+            .collect();
+        self.load_lines(file, &lines)
+    }
+
+    fn load_lines(&mut self, file: Rc<SourceFile>, lines: &[(usize, String)]) {
+        let mut conditionals = Vec::new();
+        let mut iter = lines.iter();
+        while let Some((number, text)) = iter.next() {
+            // A bad line is reported and skipped, so one run shows every error.
+            if let Err(err) = self.load_line(&file, *number, text, &mut conditionals, &mut iter) {
+                self.report(&file, lines, err);
+            }
+        }
+
+        if let Some(open) = conditionals.last() {
+            let message = match &open.flow {
+                Some(flow) => format!(".{} without .{}", flow.name, flow.block.end()),
+                None => ".if without .endif".to_string(),
+            };
+            let err = file.error(open.line, open.column, message);
+            self.report(&file, lines, err);
+        }
+    }
+
+    fn report(&mut self, file: &SourceFile, lines: &[(usize, String)], mut err: Diagnostic) {
+        if err.excerpt.is_none() && err.location.file == file.name {
+            err.excerpt = lines
+                .iter()
+                .find(|(number, _)| *number == err.location.line)
+                .map(|(_, text)| text.clone());
+        }
+        self.errors.push(err);
+    }
+
+    fn load_line(
+        &mut self,
+        file: &Rc<SourceFile>,
+        number: usize,
+        text: &str,
+        conditionals: &mut Vec<Conditional>,
+        iter: &mut std::slice::Iter<(usize, String)>,
+    ) -> Result<(), Diagnostic> {
+        if self.flow(file, number, text, conditionals)? {
+            return Ok(());
+        }
+        if self.conditional(file, number, text, conditionals)? {
+            return Ok(());
+        }
+        if conditionals.iter().any(|c| !c.active) {
+            return Ok(());
+        }
+        if let Some((offset, rest)) = directive_line(text, "macro") {
+            return self.define_macro(file, number, offset, rest, iter);
+        }
+        if directive_line(text, "endm").is_some() {
+            return Err(file.error(number, indent(text) + 1, ".endm without .macro"));
+        }
+        if directive_line(text, "struct").is_some() {
+            let result = self.define_struct(file, number, text, iter);
+            return result.inspect_err(|_| skip_block(iter, "endstruct"));
+        }
+        if directive_line(text, "enum").is_some() {
+            let result = self.define_enum(file, number, text, iter);
+            return result.inspect_err(|_| skip_block(iter, "endenum"));
+        }
+        for (end, start) in [("endstruct", ".struct"), ("endenum", ".enum")] {
+            if directive_line(text, end).is_some() {
+                return Err(file.error(
+                    number,
+                    indent(text) + 1,
+                    format!(".{} without {}", end, start),
+                ));
+            }
+        }
+        if self.invoke(file, number, text)? {
+            return Ok(());
+        }
+
+        let statement =
+            parse_line(text).map_err(|(column, message)| file.error(number, column, message))?;
+
+        if let StatementKind::Directive { name, args } = &statement.kind {
+            if name == "include" {
+                return self.include(file, number, text, &statement, args);
+            }
+        }
+
+        if let Some(label) = &statement.label {
+            let value = match &statement.kind {
+                StatementKind::Directive { name, args } if name == "const" => {
+                    match args.as_slice() {
+                        [Operand {
+                            kind: OperandKind::Expr(expr),
+                            ..
+                        }] => self.eval(expr).ok(),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            self.symbols.insert(label.clone(), value);
+        }
+
+        self.lines.push(Line {
+            file: file.clone(),
+            number,
+            text: text.to_string(),
+            statement,
+        });
+        Ok(())
+    }
+
+    // Handles `.if`, `.ifdef`, `.ifndef`, `.else` and `.endif`, returning false for
+    // any other line. Conditions inside a skipped block are not evaluated.
+    fn conditional(
+        &self,
+        file: &SourceFile,
+        number: usize,
+        text: &str,
+        open: &mut Vec<Conditional>,
+    ) -> Result<bool, Diagnostic> {
+        let column = indent(text) + 1;
+        let enclosing = open.iter().all(|c| c.active);
+        let condition = if directive_line(text, "if").is_some() {
+            enclosing.then(|| self.condition(file, number, text).map(|value| value != 0))
+        } else if directive_line(text, "ifdef").is_some() {
+            enclosing.then(|| self.is_defined(file, number, text))
+        } else if directive_line(text, "ifndef").is_some() {
+            enclosing.then(|| self.is_defined(file, number, text).map(|defined| !defined))
+        } else if directive_line(text, "else").is_some() {
+            let Some((last, outer)) = open.split_last_mut() else {
+                return Err(file.error(number, column, ".else without .if"));
+            };
+            if last.has_else {
+                return Err(file.error(number, column, "duplicate .else"));
+            }
+            last.has_else = true;
+            last.active = outer.iter().all(|c| c.active) && !last.active;
+            return Ok(true);
+        } else if directive_line(text, "endif").is_some() {
+            if open.pop().is_none() {
+                return Err(file.error(number, column, ".endif without .if"));
+            }
+            return Ok(true);
+        } else {
+            return Ok(false);
+        };
+
+        // A condition that cannot be evaluated still opens its block, so the
+        // `.else` and `.endif` that follow find it.
+        let condition = condition.unwrap_or(Ok(false));
+        open.push(Conditional {
+            line: number,
+            column,
+            active: *condition.as_ref().unwrap_or(&false),
+            has_else: false,
+            flow: None,
+        });
+        condition.map(|_| true)
+    }
+
+    // Expands `.ifz`, `.ifnz`, `.ifn`, `.ifnn`, `.else`, `.endif`, `.while`,
+    // `.endwhile`, `.repeat` and `.until` into comparisons and jumps, returning
+    // false for any other line, and for `.else` and `.endif` closing an `.if`.
+    fn flow(
+        &mut self,
+        file: &Rc<SourceFile>,
+        number: usize,
+        text: &str,
+        open: &mut Vec<Conditional>,
+    ) -> Result<bool, Diagnostic> {
+        let unlabeled;
+        let text = match flow::labeled(text) {
+            Some((column, line)) => {
+                self.errors.push(Diagnostic {
+                    excerpt: Some(text.to_string()),
+                    ..file.error(
+                        number,
+                        column,
+                        "structured blocks do not take a label on the same line",
+                    )
+                });
+                unlabeled = line;
+                unlabeled.as_str()
+            }
+            None => text,
+        };
+        let Some(directive) = flow::directive(text) else {
+            return Ok(false);
+        };
+        let column = indent(text) + 1;
+        let innermost = open.last().and_then(|last| last.flow.clone());
+        if let Directive::Else | Directive::EndIf = directive {
+            match &innermost {
+                None => return Ok(false),
+                Some(flow) if flow.block != Block::If => {
+                    let name = if let Directive::Else = directive {
+                        "else"
+                    } else {
+                        "endif"
+                    };
+                    return Err(file.error(
+                        number,
+                        column,
+                        format!(".{} expected before .{}", flow.block.end(), name),
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+        let active = open.iter().all(|c| c.active);
+        let error = |(column, message)| file.error(number, column, message);
+
+        let statements = match directive {
+            Directive::Open(block, name) => {
+                self.expansions += 1;
+                let flow = Flow {
+                    block,
+                    name,
+                    id: self.expansions,
+                };
+                let statements = if active {
+                    flow::open(text, &flow).map_err(error)?
+                } else {
+                    Vec::new()
+                };
+                open.push(Conditional {
+                    line: number,
+                    column,
+                    active,
+                    has_else: false,
+                    flow: Some(flow),
+                });
+                statements
+            }
+            Directive::Else => {
+                let last = open.last_mut().unwrap();
+                if last.has_else {
+                    return Err(file.error(number, column, "duplicate .else"));
+                }
+                last.has_else = true;
+                match active {
+                    true => flow::otherwise(text, last.flow.as_ref().unwrap()),
+                    false => Vec::new(),
+                }
+            }
+            Directive::EndIf | Directive::EndWhile | Directive::Until => {
+                let (end, block) = match directive {
+                    Directive::EndWhile => ("endwhile", Block::While),
+                    Directive::Until => ("until", Block::Repeat),
+                    _ => ("endif", Block::If),
+                };
+                let Some(flow) = innermost.filter(|flow| flow.block == block) else {
+                    let start = match block {
+                        Block::While => "while",
+                        _ => "repeat",
+                    };
+                    return Err(file.error(number, column, format!(".{} without .{}", end, start)));
+                };
+                let has_else = open.pop().unwrap().has_else;
+                match active {
+                    true => flow::close(text, &flow, has_else).map_err(error)?,
+                    false => Vec::new(),
+                }
+            }
+        };
+        for statement in statements {
+            self.lines.push(Line {
+                file: file.clone(),
+                number,
+                text: text.to_string(),
+                statement,
+            });
+        }
+        Ok(true)
+    }
+
+    fn condition(&self, file: &SourceFile, number: usize, text: &str) -> Result<i64, Diagnostic> {
+        let (column, expr) = conditional_arg(file, number, text, "expected an expression")?;
+        self.eval(&expr)
+            .map_err(|err| file.error(number, column, constant_error(err, ".if")))
+    }
+
+    fn is_defined(&self, file: &SourceFile, number: usize, text: &str) -> Result<bool, Diagnostic> {
+        match conditional_arg(file, number, text, "expected a symbol name")? {
+            (_, Expr::Symbol(name)) => Ok(self.symbols.contains_key(&name)),
+            (column, _) => Err(file.error(number, column, "expected a symbol name")),
+        }
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<i64, ExprError> {
+        expr.eval(&|name| self.symbols.get(name).copied().flatten(), 0)
+    }
+
+    fn include(
+        &mut self,
+        file: &Rc<SourceFile>,
+        number: usize,
+        text: &str,
+        statement: &Statement,
+        args: &[Operand],
+    ) -> Result<(), Diagnostic> {
+        let [Operand {
+            kind: OperandKind::Str(target),
+            column,
+        }] = args
+        else {
+            return Err(file.error(number, statement.column, "expected a file name string"));
+        };
+
+        let Some(path) = resolve(self.include_dirs, self.files, file, target) else {
+            return Err(file.error(
+                number,
+                *column,
+                format!("cannot find include file '{}'", target),
+            ));
+        };
+        let key = self.files.canonical(&path);
+        let name = path.display().to_string();
+        if let Some(start) = self.active.iter().position(|(active, _)| *active == key) {
+            let mut cycle: Vec<&str> = self.active[start..]
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .collect();
+            cycle.push(&name);
+            return Err(file.error(
+                number,
+                *column,
+                format!("include cycle: {}", cycle.join(" -> ")),
+            ));
+        }
+        let source = self.files.read_to_string(&path).map_err(|err| {
+            file.error(number, *column, format!("cannot read '{}': {}", name, err))
+        })?;
+
+        // Kept for its label and so the listing shows where the file was pulled in.
+        self.lines.push(Line {
+            file: file.clone(),
+            number,
+            text: text.to_string(),
+            statement: Statement {
+                label: statement.label.clone(),
+                kind: StatementKind::Empty,
+                column: statement.column,
+            },
+        });
+
+        let child = Rc::new(SourceFile {
+            name: name.clone(),
+            path: Some(path),
+            origin: Some((
+                file.clone(),
+                Origin::Include(file.location(number, statement.column)),
+            )),
+        });
+        self.active.push((key, name));
+        self.load_file(child, &source);
+        self.active.pop();
+        Ok(())
+    }
+
+    fn define_macro<'l>(
+        &mut self,
+        file: &Rc<SourceFile>,
+        number: usize,
+        offset: usize,
+        header: &str,
+        lines: &mut impl Iterator<Item = &'l (usize, String)>,
+    ) -> Result<(), Diagnostic> {
+        let column = indent(header) + offset + 1;
+        let header = header.trim_start();
+        let name: String = header
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .collect();
+
+        let mut body = Vec::new();
+        loop {
+            let Some((line, text)) = lines.next() else {
+                return Err(file.error(
+                    number,
+                    offset - 5,
+                    format!("macro '{}' is missing .endm", name),
+                ));
+            };
+            if directive_line(text, "endm").is_some() {
+                break;
+            }
+            if directive_line(text, "macro").is_some() {
+                let err = file.error(
+                    *line,
+                    indent(text) + 1,
+                    "macro definitions cannot be nested",
+                );
+                let _ = lines.find(|(_, text)| directive_line(text, "endm").is_some());
+                return Err(err);
+            }
+            body.push((*line, text.clone()));
+        }
+
+        // Checked after the body is taken, so a bad header does not leave the body
+        // to be assembled as code.
+        if !is_identifier(&name) {
+            return Err(file.error(number, column, "expected a macro name"));
+        }
+        if lookup(&name.to_uppercase()).is_some() {
+            return Err(file.error(
+                number,
+                column,
+                format!("'{}' is an instruction and cannot be a macro name", name),
+            ));
+        }
+        if self.macros.contains_key(&name.to_uppercase()) {
+            return Err(file.error(
+                number,
+                column,
+                format!("macro '{}' is already defined", name),
+            ));
+        }
+        let params = parse_params(&header[name.len()..], column - 1 + name.chars().count())
+            .map_err(|(column, message)| file.error(number, column, message))?;
+
+        self.macros.insert(
+            name.to_uppercase(),
+            Rc::new(Macro {
+                name,
+                params,
+                body,
+                file: file.clone(),
+            }),
+        );
+        Ok(())
+    }
+
+    // `.struct Name` ... `.endstruct`: each field label becomes the constant
+    // `Name.field` with its offset, and `Name.size` is the size of the record.
+    fn define_struct<'l>(
+        &mut self,
+        file: &Rc<SourceFile>,
+        number: usize,
+        text: &str,
+        lines: &mut impl Iterator<Item = &'l (usize, String)>,
+    ) -> Result<(), Diagnostic> {
+        let (column, name) = block_name(file, number, text)?;
+        let Some(name) = name else {
+            return Err(file.error(number, column, "expected a struct name"));
+        };
+        self.push_empty(file, number, text);
+
+        let mut offset = 0;
+        for (line, text) in lines.by_ref() {
+            let line = *line;
+            if directive_line(text, "endstruct").is_some() {
+                self.push_constant(file, line, text, format!("{}.size", name), offset);
+                return Ok(());
+            }
+            let statement =
+                parse_line(text).map_err(|(column, message)| file.error(line, column, message))?;
+            let size = match &statement.kind {
+                StatementKind::Empty => 0,
+                StatementKind::Directive { name, args }
+                    if matches!(name.as_str(), "byte" | "short" | "space" | "align") =>
+                {
+                    let count = match (name.as_str(), args.as_slice()) {
+                        ("byte" | "short", []) => 1,
+                        (_, [arg]) => self.size(file, line, arg)?,
+                        _ => {
+                            return Err(file.error(
+                                line,
+                                statement.column,
+                                "expected exactly one argument",
+                            ))
+                        }
+                    };
+                    match name.as_str() {
+                        "short" => count * 2,
+                        "align" if count == 0 || count & (count - 1) != 0 => {
+                            return Err(file.error(
+                                line,
+                                args[0].column,
+                                format!("alignment {} is not a power of two", count),
+                            ))
+                        }
+                        "align" => (count - offset % count) % count,
+                        _ => count,
+                    }
+                }
+                _ => {
+                    return Err(file.error(
+                        line,
+                        statement.column,
+                        "expected .byte, .short, .space or .align inside .struct",
+                    ))
+                }
+            };
+            match &statement.label {
+                Some(label) if label.starts_with(['.', ':']) => {
+                    return Err(file.error(line, 1, "struct fields need a plain name"))
+                }
+                Some(label) => {
+                    self.push_constant(file, line, text, format!("{}.{}", name, label), offset)
+                }
+                None => self.push_empty(file, line, text),
+            }
+            offset += size;
+        }
+        Err(file.error(
+            number,
+            indent(text) + 1,
+            format!("struct '{}' is missing .endstruct", name),
+        ))
+    }
+
+    // `.enum [Name]` ... `.endenum`: one constant per line, counting up from 0
+    // or from the last `NAME = value`. A named enum qualifies them as `Name.NAME`.
+    fn define_enum<'l>(
+        &mut self,
+        file: &Rc<SourceFile>,
+        number: usize,
+        text: &str,
+        lines: &mut impl Iterator<Item = &'l (usize, String)>,
+    ) -> Result<(), Diagnostic> {
+        let (_, name) = block_name(file, number, text)?;
+        self.push_empty(file, number, text);
+
+        let mut next = Some(0);
+        for (line, text) in lines.by_ref() {
+            let line = *line;
+            if directive_line(text, "endenum").is_some() {
+                self.push_empty(file, line, text);
+                return Ok(());
+            }
+            let Some(member) = parse_member(text)
+                .map_err(|(column, message)| file.error(line, column, message))?
+            else {
+                self.push_empty(file, line, text);
+                continue;
+            };
+            let value = match &member.value {
+                Some(expr) => self
+                    .eval(expr)
+                    .map_err(|err| file.error(line, member.column, constant_error(err, ".enum")))?,
+                None => next.ok_or_else(|| {
+                    file.error(
+                        line,
+                        member.column,
+                        "value after the previous one overflows",
+                    )
+                })?,
+            };
+            if !(-0x8000..=0xFFFF).contains(&value) {
+                return Err(file.error(
+                    line,
+                    member.column,
+                    format!("value {} does not fit in 16 bits", value),
+                ));
+            }
+            let qualified = match &name {
+                Some(name) => format!("{}.{}", name, member.name),
+                None => member.name,
+            };
+            self.push_constant(file, line, text, qualified, value);
+            next = value.checked_add(1);
+        }
+        Err(file.error(number, indent(text) + 1, ".enum is missing .endenum"))
+    }
+
+    // A size inside `.struct`, known when the line is read.
+    fn size(&self, file: &SourceFile, line: usize, arg: &Operand) -> Result<i64, Diagnostic> {
+        let OperandKind::Expr(expr) = &arg.kind else {
+            return Err(file.error(line, arg.column, "expected a numeric value"));
+        };
+        match self.eval(expr) {
+            Ok(value) if value >= 0 => Ok(value),
+            Ok(value) => Err(file.error(line, arg.column, format!("invalid size {}", value))),
+            Err(err) => Err(file.error(line, arg.column, constant_error(err, ".struct"))),
+        }
+    }
+
+    // Keeps a line that produces nothing, so the listing still shows it.
+    fn push_empty(&mut self, file: &Rc<SourceFile>, number: usize, text: &str) {
+        self.lines.push(Line {
+            file: file.clone(),
+            number,
+            text: text.to_string(),
+            statement: Statement {
+                label: None,
+                kind: StatementKind::Empty,
+                column: indent(text) + 1,
+            },
+        });
+    }
+
+    fn push_constant(
+        &mut self,
+        file: &Rc<SourceFile>,
+        number: usize,
+        text: &str,
+        name: String,
+        value: i64,
+    ) {
+        let column = indent(text) + 1;
+        self.symbols.insert(name.clone(), Some(value));
+        self.lines.push(Line {
+            file: file.clone(),
+            number,
+            text: text.to_string(),
+            statement: Statement {
+                label: Some(name),
+                kind: StatementKind::Directive {
+                    name: "const".to_string(),
+                    args: vec![Operand {
+                        kind: OperandKind::Expr(Expr::Number(value)),
+                        column,
+                    }],
+                },
+                column,
+            },
+        });
+    }
+
+    // Expands `text` if it calls a macro, optionally after a label. Returns false for
+    // any other line, leaving it to the parser.
+    fn invoke(
+        &mut self,
+        file: &Rc<SourceFile>,
+        number: usize,
+        text: &str,
+    ) -> Result<bool, Diagnostic> {
+        let Ok(tokens) = tokenize(text) else {
+            return Ok(false);
+        };
+        let Ok((label, start)) = label(&tokens) else {
+            return Ok(false);
+        };
+        let Some(Spanned {
+            token: Token::Ident(name),
+            column,
+        }) = tokens.get(start)
+        else {
+            return Ok(false);
+        };
+        let Some(definition) = self.macros.get(&name.to_uppercase()).cloned() else {
+            return Ok(false);
+        };
+        let column = *column;
+
+        self.lines.push(Line {
+            file: file.clone(),
+            number,
+            text: text.to_string(),
+            statement: Statement {
+                label,
+                kind: StatementKind::Empty,
+                column,
+            },
+        });
+
+        if self.depth == MAX_EXPANSION_DEPTH {
+            return Err(file.error(
+                number,
+                column,
+                format!("macro '{}' expands too deeply", definition.name),
+            ));
+        }
+        let offset = column - 1 + name.chars().count();
+        let rest: String = text.chars().skip(offset).collect();
+        self.expansions += 1;
+        let body = definition
+            .expand(&split_args(&rest, offset), self.expansions)
+            .map_err(|message| file.error(number, column, message))?;
+
+        let child = Rc::new(SourceFile {
+            name: definition.file.name.clone(),
+            path: definition.file.path.clone(),
+            origin: Some((
+                file.clone(),
+                Origin::Macro(definition.name.clone(), file.location(number, column)),
+            )),
+        });
+        self.depth += 1;
+        self.load_lines(child, &body);
+        self.depth -= 1;
+        Ok(true)
+    }
+}
+
+// Looks for `target` next to `file`, then in each include directory.
+pub(super) fn resolve(
+    include_dirs: &[PathBuf],
+    files: &dyn FileSystem,
+    file: &SourceFile,
+    target: &str,
+) -> Option<PathBuf> {
+    let local = match file.path.as_ref().and_then(|path| path.parent()) {
+        Some(dir) => dir.join(target),
+        None => PathBuf::from(target),
+    };
+    std::iter::once(local)
+        .chain(include_dirs.iter().map(|dir| dir.join(target)))
+        .find(|path| files.is_file(path))
+}
+
+// Drops the rest of a `.struct` or `.enum` that failed, up to its end.
+fn skip_block(lines: &mut std::slice::Iter<(usize, String)>, end: &str) {
+    let _ = lines.find(|(_, text)| directive_line(text, end).is_some());
+}
+
+// The optional name after `.struct` or `.enum`, and the column where it goes.
+fn block_name(
+    file: &SourceFile,
+    number: usize,
+    text: &str,
+) -> Result<(usize, Option<String>), Diagnostic> {
+    let statement =
+        parse_line(text).map_err(|(column, message)| file.error(number, column, message))?;
+    let StatementKind::Directive { args, .. } = statement.kind else {
+        unreachable!("checked with directive_line");
+    };
+    match args.as_slice() {
+        [] => Ok((text.chars().count() + 1, None)),
+        [Operand {
+            kind: OperandKind::Expr(Expr::Symbol(name)),
+            ..
+        }] if is_identifier(name) => Ok((statement.column, Some(name.clone()))),
+        [arg, ..] => Err(file.error(number, arg.column, "expected a name")),
+    }
+}
+
+fn constant_error(err: ExprError, block: &str) -> String {
+    match err {
+        ExprError::Undefined(name) => {
+            format!(
+                "symbol '{}' must be a constant defined before {}",
+                name, block
+            )
+        }
+        ExprError::Invalid(message) => message,
+    }
+}
+
+fn conditional_arg(
+    file: &SourceFile,
+    number: usize,
+    text: &str,
+    expected: &str,
+) -> Result<(usize, Expr), Diagnostic> {
+    let statement =
+        parse_line(text).map_err(|(column, message)| file.error(number, column, message))?;
+    match statement.kind {
+        StatementKind::Directive { args, .. } => match args.as_slice() {
+            [Operand {
+                kind: OperandKind::Expr(expr),
+                column,
+            }] => Ok((*column, expr.clone())),
+            _ => Err(file.error(number, statement.column, expected)),
+        },
+        _ => Err(file.error(number, statement.column, expected)),
+    }
+}
+
+// Matches a line starting with `.name`, returning the char offset and text that
+// follow the directive. Used where the line cannot be tokenized as a whole.
+pub(super) fn directive_line<'t>(text: &'t str, name: &str) -> Option<(usize, &'t str)> {
+    let rest = text.trim_start().strip_prefix('.')?;
+    if !rest.get(..name.len())?.eq_ignore_ascii_case(name) {
+        return None;
+    }
+    let rest = &rest[name.len()..];
+    if rest.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+    Some((text.chars().count() - rest.chars().count(), rest))
+}
+
+fn indent(text: &str) -> usize {
+    text.chars().take_while(|c| c.is_whitespace()).count()
+}
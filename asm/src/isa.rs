@@ -0,0 +1,107 @@
+// The opcodes and jump conditions of the cupana machine, as encoded by the
+// assembler and decoded by the machine and the disassembler.
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    NOP = 0x00,
+    HLT = 0x01,
+    MOV = 0x02,
+    PHR = 0x03,
+    PLR = 0x04,
+    ADD = 0x05,
+    SUB = 0x06,
+    MUL = 0x07,
+    DIV = 0x08,
+    MOD = 0x09,
+    INC = 0x0A,
+    DEC = 0x0B,
+    AND = 0x0C,
+    OR = 0x0D,
+    XOR = 0x0E,
+    SHL = 0x10,
+    SHR = 0x11,
+    NOT = 0x0F,
+    CMP = 0x12,
+    JMP = 0x13,
+    JPC = 0x14,
+    JSB = 0x15,
+    RSB = 0x16,
+    CLI = 0x17,
+    SEI = 0x18,
+    RSI = 0x19,
+    NONE = 0x1F,
+}
+
+impl From<u8> for Opcode {
+    fn from(value: u8) -> Self {
+        match value {
+            0x00 => Opcode::NOP,
+            0x01 => Opcode::HLT,
+            0x02 => Opcode::MOV,
+            0x03 => Opcode::PHR,
+            0x04 => Opcode::PLR,
+            0x05 => Opcode::ADD,
+            0x06 => Opcode::SUB,
+            0x07 => Opcode::MUL,
+            0x08 => Opcode::DIV,
+            0x09 => Opcode::MOD,
+            0x0A => Opcode::INC,
+            0x0B => Opcode::DEC,
+            0x0C => Opcode::AND,
+            0x0D => Opcode::OR,
+            0x0E => Opcode::XOR,
+            0x0F => Opcode::NOT,
+            0x10 => Opcode::SHL,
+            0x11 => Opcode::SHR,
+            0x12 => Opcode::CMP,
+            0x13 => Opcode::JMP,
+            0x14 => Opcode::JPC,
+            0x15 => Opcode::JSB,
+            0x16 => Opcode::RSB,
+            0x17 => Opcode::CLI,
+            0x18 => Opcode::SEI,
+            0x19 => Opcode::RSI,
+            _ => Opcode::NONE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JumpMode {
+    Zero = 0x00,
+    NotZero = 0x01,
+    Negative = 0x02,
+    NotNegative = 0x03,
+    Overflow = 0x04,
+    NotOverflow = 0x05,
+    None = 0xFF,
+}
+
+impl From<u8> for JumpMode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => JumpMode::Zero,
+            1 => JumpMode::NotZero,
+            2 => JumpMode::Negative,
+            3 => JumpMode::NotNegative,
+            4 => JumpMode::Overflow,
+            5 => JumpMode::NotOverflow,
+            _ => JumpMode::None,
+        }
+    }
+}
+
+impl JumpMode {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            JumpMode::Zero => "JZ",
+            JumpMode::NotZero => "JNZ",
+            JumpMode::Negative => "JN",
+            JumpMode::NotNegative => "JNN",
+            JumpMode::Overflow => "JO",
+            JumpMode::NotOverflow => "JNO",
+            JumpMode::None => "JPC",
+        }
+    }
+}
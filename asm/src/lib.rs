@@ -0,0 +1,4 @@
+pub mod assembler;
+pub mod isa;
+pub mod memory_map;
+pub mod object;
@@ -0,0 +1,17 @@
+// The address space of the cupana machine, described in machine.md.
+
+pub const ROM_SIZE: usize = 0x8000;
+pub const RAM_SIZE: usize = 0x6000;
+pub const STACK_SIZE: usize = 0x1000;
+pub const DEVICE_SIZE: usize = 0x1000;
+pub const MEMORY_SIZE: usize = ROM_SIZE + RAM_SIZE + STACK_SIZE + DEVICE_SIZE;
+
+pub const ROM_BASE: u16 = 0x0000;
+pub const RAM_BASE: u16 = ROM_BASE + ROM_SIZE as u16;
+pub const STACK_BASE: u16 = RAM_BASE + RAM_SIZE as u16;
+pub const DEVICE_BASE: u16 = STACK_BASE + STACK_SIZE as u16;
+
+pub const ROM_END: u16 = ROM_BASE + ROM_SIZE as u16 - 1;
+pub const RAM_END: u16 = RAM_BASE + RAM_SIZE as u16 - 1;
+pub const STACK_END: u16 = STACK_BASE + STACK_SIZE as u16 - 1;
+pub const DEVICE_END: u16 = 0xFFFF;
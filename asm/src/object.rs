@@ -0,0 +1,429 @@
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"CUPO";
+const ARCHIVE_MAGIC: &[u8; 4] = b"CUPA";
+const VERSION: u8 = 2;
+
+// What a relocated field holds: the whole address or one of its bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Part {
+    Full,
+    Lo,
+    Hi,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    // Start of a section of the same object.
+    Section(String),
+    // A symbol imported with `.extern`.
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub offset: usize,
+    pub size: u8,
+    pub part: Part,
+    pub target: Target,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub size: usize,
+    // The linker places the section at a multiple of this.
+    pub align: usize,
+    // Empty for `.bss` sections, which only reserve `size` zeroed bytes.
+    pub data: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    // None for absolute values such as constants.
+    pub section: Option<String>,
+    pub value: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub exports: Vec<Symbol>,
+    pub imports: Vec<String>,
+}
+
+// A static library: objects stored together so the linker can take only the
+// members a program needs.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Archive {
+    pub members: Vec<(String, Object)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ObjectError(pub String);
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid object file: {}", self.0)
+    }
+}
+
+// Sections are grouped by kind for placement: `.text.math` is code like `.text`.
+pub fn section_kind(name: &str) -> Option<&'static str> {
+    [".text", ".rodata", ".data", ".bss"]
+        .into_iter()
+        .find(|kind| name == *kind || name.starts_with(&format!("{}.", kind)))
+}
+
+impl Object {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer(MAGIC.to_vec());
+        out.u8(VERSION);
+
+        out.u32(self.sections.len() as u32);
+        for section in &self.sections {
+            out.str(&section.name);
+            out.u32(section.size as u32);
+            out.u32(section.align as u32);
+            out.u8(!section.data.is_empty() as u8);
+            out.0.extend_from_slice(&section.data);
+            out.u32(section.relocations.len() as u32);
+            for relocation in &section.relocations {
+                out.u32(relocation.offset as u32);
+                out.u8(relocation.size);
+                out.u8(relocation.part as u8);
+                match &relocation.target {
+                    Target::Section(name) => {
+                        out.u8(0);
+                        out.str(name);
+                    }
+                    Target::Symbol(name) => {
+                        out.u8(1);
+                        out.str(name);
+                    }
+                }
+                out.i64(relocation.addend);
+            }
+        }
+
+        out.u32(self.exports.len() as u32);
+        for symbol in &self.exports {
+            out.str(&symbol.name);
+            out.str(symbol.section.as_deref().unwrap_or(""));
+            out.i64(symbol.value);
+        }
+
+        out.u32(self.imports.len() as u32);
+        for name in &self.imports {
+            out.str(name);
+        }
+        out.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ObjectError> {
+        let mut input = Reader { bytes, pos: 0 };
+        if input.take(4)? != MAGIC {
+            return Err(ObjectError("bad magic number".to_string()));
+        }
+        let version = input.u8()?;
+        if version != VERSION {
+            return Err(ObjectError(format!("unsupported version {}", version)));
+        }
+
+        let mut object = Object::default();
+        for _ in 0..input.u32()? {
+            let name = input.str()?;
+            let size = input.u32()? as usize;
+            let align = input.u32()? as usize;
+            if !align.is_power_of_two() {
+                return Err(ObjectError(format!(
+                    "section '{}' has invalid alignment {}",
+                    name, align
+                )));
+            }
+            let data = match input.u8()? {
+                0 => Vec::new(),
+                _ => input.take(size)?.to_vec(),
+            };
+            let mut relocations = Vec::new();
+            for _ in 0..input.u32()? {
+                let offset = input.u32()? as usize;
+                let size = input.u8()?;
+                if !matches!(size, 1 | 2) {
+                    return Err(ObjectError(format!("invalid relocation size {}", size)));
+                }
+                let part = match input.u8()? {
+                    0 => Part::Full,
+                    1 => Part::Lo,
+                    2 => Part::Hi,
+                    part => return Err(ObjectError(format!("unknown relocation part {}", part))),
+                };
+                let target = match input.u8()? {
+                    0 => Target::Section(input.str()?),
+                    1 => Target::Symbol(input.str()?),
+                    kind => return Err(ObjectError(format!("unknown relocation target {}", kind))),
+                };
+                if offset + size as usize > data.len() {
+                    return Err(ObjectError(format!(
+                        "relocation at {} is outside section '{}'",
+                        offset, name
+                    )));
+                }
+                relocations.push(Relocation {
+                    offset,
+                    size,
+                    part,
+                    target,
+                    addend: input.i64()?,
+                });
+            }
+            object.sections.push(Section {
+                name,
+                size,
+                align,
+                data,
+                relocations,
+            });
+        }
+
+        for _ in 0..input.u32()? {
+            let name = input.str()?;
+            let section = Some(input.str()?).filter(|section| !section.is_empty());
+            object.exports.push(Symbol {
+                name,
+                section,
+                value: input.i64()?,
+            });
+        }
+        for _ in 0..input.u32()? {
+            object.imports.push(input.str()?);
+        }
+        let undeclared = object
+            .sections
+            .iter()
+            .flat_map(|section| &section.relocations)
+            .find_map(|relocation| match &relocation.target {
+                Target::Symbol(symbol) if !object.imports.contains(symbol) => Some(symbol),
+                _ => None,
+            });
+        if let Some(symbol) = undeclared {
+            return Err(ObjectError(format!(
+                "relocation against undeclared symbol '{}'",
+                symbol
+            )));
+        }
+
+        if input.pos != bytes.len() {
+            return Err(ObjectError("trailing data".to_string()));
+        }
+        Ok(object)
+    }
+}
+
+impl Archive {
+    pub fn is_archive(bytes: &[u8]) -> bool {
+        bytes.starts_with(ARCHIVE_MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer(ARCHIVE_MAGIC.to_vec());
+        out.u8(VERSION);
+        out.u32(self.members.len() as u32);
+        for (name, object) in &self.members {
+            let bytes = object.to_bytes();
+            out.str(name);
+            out.u32(bytes.len() as u32);
+            out.0.extend_from_slice(&bytes);
+        }
+        out.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ObjectError> {
+        let mut input = Reader { bytes, pos: 0 };
+        if input.take(4)? != ARCHIVE_MAGIC {
+            return Err(ObjectError("bad magic number".to_string()));
+        }
+        let version = input.u8()?;
+        if version != VERSION {
+            return Err(ObjectError(format!("unsupported version {}", version)));
+        }
+
+        let mut archive = Archive::default();
+        for _ in 0..input.u32()? {
+            let name = input.str()?;
+            let len = input.u32()? as usize;
+            let object = Object::from_bytes(input.take(len)?)
+                .map_err(|err| ObjectError(format!("member '{}': {}", name, err.0)))?;
+            archive.members.push((name, object));
+        }
+        if input.pos != bytes.len() {
+            return Err(ObjectError("trailing data".to_string()));
+        }
+        Ok(archive)
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ObjectError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| ObjectError("unexpected end of file".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ObjectError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ObjectError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, ObjectError> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, ObjectError> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| ObjectError("invalid string".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Object {
+        Object {
+            sections: vec![
+                Section {
+                    name: ".text".to_string(),
+                    size: 3,
+                    align: 1,
+                    data: vec![0b1001_1001, 0, 0],
+                    relocations: vec![Relocation {
+                        offset: 1,
+                        size: 2,
+                        part: Part::Full,
+                        target: Target::Symbol("imprime".to_string()),
+                        addend: -2,
+                    }],
+                },
+                Section {
+                    name: ".bss".to_string(),
+                    size: 16,
+                    align: 2,
+                    data: Vec::new(),
+                    relocations: Vec::new(),
+                },
+            ],
+            exports: vec![
+                Symbol {
+                    name: "inicio".to_string(),
+                    section: Some(".text".to_string()),
+                    value: 0,
+                },
+                Symbol {
+                    name: "TAMANHO".to_string(),
+                    section: None,
+                    value: 16,
+                },
+            ],
+            imports: vec!["imprime".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let object = sample();
+        assert_eq!(Object::from_bytes(&object.to_bytes()), Ok(object));
+    }
+
+    #[test]
+    fn test_invalid_objects() {
+        let bytes = sample().to_bytes();
+        assert_eq!(
+            Object::from_bytes(b"ELF\0"),
+            Err(ObjectError("bad magic number".to_string()))
+        );
+        assert_eq!(
+            Object::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ObjectError("unexpected end of file".to_string()))
+        );
+
+        let mut object = sample();
+        object.sections[0].relocations[0].size = 3;
+        assert_eq!(
+            Object::from_bytes(&object.to_bytes()),
+            Err(ObjectError("invalid relocation size 3".to_string()))
+        );
+        let mut object = sample();
+        object.imports.clear();
+        assert_eq!(
+            Object::from_bytes(&object.to_bytes()),
+            Err(ObjectError(
+                "relocation against undeclared symbol 'imprime'".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let archive = Archive {
+            members: vec![
+                ("tela.o".to_string(), sample()),
+                ("vazio.o".to_string(), Object::default()),
+            ],
+        };
+        let bytes = archive.to_bytes();
+        assert!(Archive::is_archive(&bytes));
+        assert!(!Archive::is_archive(&sample().to_bytes()));
+        assert_eq!(Archive::from_bytes(&bytes), Ok(archive));
+
+        let mut bytes = bytes;
+        bytes[23..27].copy_from_slice(b"CUPX");
+        assert_eq!(
+            Archive::from_bytes(&bytes),
+            Err(ObjectError("member 'tela.o': bad magic number".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_section_kind() {
+        assert_eq!(section_kind(".text"), Some(".text"));
+        assert_eq!(section_kind(".rodata.tabelas"), Some(".rodata"));
+        assert_eq!(section_kind(".textos"), None);
+    }
+}
@@ -0,0 +1,125 @@
+use cupana_asm::assembler::{assemble, Diagnostic, Options, Sources};
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+// Assembles the statements given, separated by `;`, into a `[u8; N]` when the
+// crate is compiled. Assembler errors become compile errors on the token they
+// point to.
+#[proc_macro]
+pub fn casm(input: TokenStream) -> TokenStream {
+    let mut lines = vec![Line::default()];
+    for tree in input {
+        match &tree {
+            TokenTree::Punct(punct) if punct.as_char() == ';' => lines.push(Line::default()),
+            _ => lines.last_mut().unwrap().push(&tree),
+        }
+    }
+    let source: String = lines.iter().map(|line| line.text.clone() + "\n").collect();
+
+    match assemble(&Sources::from(source.as_str()), &Options::default()) {
+        Ok(image) => {
+            let mut bytes = TokenStream::new();
+            for byte in image.rom {
+                bytes.extend([
+                    TokenTree::Literal(Literal::u8_suffixed(byte)),
+                    TokenTree::Punct(Punct::new(',', Spacing::Alone)),
+                ]);
+            }
+            TokenTree::Group(Group::new(Delimiter::Bracket, bytes)).into()
+        }
+        Err(errors) => {
+            let mut tokens = TokenStream::new();
+            for err in &errors {
+                tokens.extend(compile_error(err, span(&lines, err)));
+            }
+            tokens.extend("[0u8; 0]".parse::<TokenStream>().unwrap());
+            TokenTree::Group(Group::new(Delimiter::Brace, tokens)).into()
+        }
+    }
+}
+
+// A statement turned back into source text, with the column each token starts at.
+#[derive(Default)]
+struct Line {
+    text: String,
+    tokens: Vec<(usize, Span)>,
+    end: Option<Span>,
+}
+
+impl Line {
+    fn push(&mut self, tree: &TokenTree) {
+        // Tokens written apart in the macro are kept apart, so `R0*` and `.org`
+        // stay whole.
+        let span = tree.span();
+        let joined = self.end.is_some_and(|end| {
+            end.line() == span.start().line() && end.column() == span.start().column()
+        });
+        if !self.text.is_empty() && !joined {
+            self.text.push(' ');
+        }
+        self.tokens.push((self.text.chars().count() + 1, span));
+
+        match tree {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::None => ("", ""),
+                };
+                self.text.push_str(open);
+                self.end = Some(group.span_open().end());
+                for tree in group.stream() {
+                    self.push(&tree);
+                }
+                self.text.push_str(close);
+            }
+            _ => self.text.push_str(&tree.to_string()),
+        }
+        self.end = Some(span.end());
+    }
+}
+
+// The token of the macro input an error points to.
+fn span(lines: &[Line], err: &Diagnostic) -> Span {
+    let Some(line) = err
+        .location
+        .line
+        .checked_sub(1)
+        .and_then(|idx| lines.get(idx))
+    else {
+        return Span::call_site();
+    };
+    line.tokens
+        .iter()
+        .take_while(|(column, _)| *column <= err.location.column.max(1))
+        .last()
+        .or(line.tokens.first())
+        .map_or(Span::call_site(), |(_, span)| *span)
+}
+
+fn compile_error(err: &Diagnostic, span: Span) -> TokenStream {
+    let mut message = err.message.clone();
+    for origin in &err.backtrace {
+        message += &format!("\n    {}", origin);
+    }
+    let mut literal = Literal::string(&message);
+    literal.set_span(span);
+    let mut tokens = vec![
+        TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+        TokenTree::Punct(Punct::new(':', Spacing::Alone)),
+        TokenTree::Ident(Ident::new("core", span)),
+        TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+        TokenTree::Punct(Punct::new(':', Spacing::Alone)),
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+        TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenTree::Literal(literal).into(),
+        )),
+        TokenTree::Punct(Punct::new(';', Spacing::Alone)),
+    ];
+    for token in &mut tokens {
+        token.set_span(span);
+    }
+    tokens.into_iter().collect()
+}
@@ -0,0 +1,562 @@
+use crate::machine::{extract_registers_from_byte, RESET_VECTOR};
+use crate::memory::Memory;
+use cupana_asm::isa::{JumpMode, Opcode};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Register(u8),
+    Indirect(u8),
+    Literal(u16),
+    Byte(u8),
+    // Target of a jump or call, printed as a label when one is known.
+    Address(u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub mnemonic: String,
+    pub operands: Vec<Operand>,
+    pub len: usize,
+}
+
+fn two_operands(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::MOV
+            | Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::MOD
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::CMP
+    )
+}
+
+// Decodes the instruction at the start of `bytes`. Only the forms listed in
+// machine.md are accepted, so anything else can be kept as data and assembled back.
+pub fn decode(bytes: &[u8]) -> Option<Instruction> {
+    use Operand::*;
+
+    let (&first, rest) = bytes.split_first()?;
+    let opcode = Opcode::from(first >> 3);
+    let byte = (first >> 2) & 1 == 1;
+    let mode = first & 0b11;
+
+    let register = |idx: usize| rest.get(idx).copied().filter(|&reg| reg < 16);
+    let registers = || rest.first().map(|&b| extract_registers_from_byte(b));
+    let word = |idx: usize| Some(u16::from_le_bytes([*rest.get(idx)?, *rest.get(idx + 1)?]));
+    let condition = |mode: u8| match JumpMode::from(mode) {
+        JumpMode::None => None,
+        condition => Some(condition.mnemonic().to_string()),
+    };
+
+    let mut mnemonic = format!("{:?}{}", opcode, if byte { "B" } else { "" });
+    let (operands, len) = match (opcode, byte, mode) {
+        (
+            Opcode::NOP | Opcode::HLT | Opcode::RSB | Opcode::CLI | Opcode::SEI | Opcode::RSI,
+            false,
+            0,
+        ) => (vec![], 0),
+        (Opcode::PHR | Opcode::PLR, false, 0) | (Opcode::INC | Opcode::DEC | Opcode::NOT, _, 0) => {
+            (vec![Register(register(0)?)], 1)
+        }
+        (Opcode::JMP | Opcode::JSB, false, 0) => (vec![Indirect(register(0)?)], 1),
+        (Opcode::JMP | Opcode::JSB, false, 1) => (vec![Address(word(0)?)], 2),
+        (Opcode::JPC, false, 0) => {
+            let (mode, reg) = registers()?;
+            mnemonic = condition(mode)?;
+            (vec![Register(reg)], 1)
+        }
+        (Opcode::JPC, false, 1) => {
+            mnemonic = condition(*rest.first()?)?;
+            (vec![Address(word(1)?)], 3)
+        }
+        (opcode, _, 0) if two_operands(opcode) => {
+            let (dest, orig) = registers()?;
+            (vec![Register(dest), Register(orig)], 1)
+        }
+        (opcode, false, 1) if two_operands(opcode) => {
+            (vec![Register(register(0)?), Literal(word(1)?)], 3)
+        }
+        (opcode, true, 1) if two_operands(opcode) => {
+            (vec![Register(register(0)?), Byte(*rest.get(1)?)], 2)
+        }
+        (Opcode::MOV, _, 2) => {
+            let (dest, orig) = registers()?;
+            (vec![Indirect(dest), Register(orig)], 1)
+        }
+        (Opcode::MOV, false, 3) => {
+            let (dest, orig) = registers()?;
+            (vec![Register(dest), Indirect(orig)], 1)
+        }
+        _ => return None,
+    };
+    Some(Instruction {
+        mnemonic,
+        operands,
+        len: len + 1,
+    })
+}
+
+// Reads a symbol map with one `<name> <hex address>` per line, the format written
+// by `cupana link -m` and used in the symbol table of listings.
+pub fn parse_symbols(text: &str) -> Result<BTreeMap<u16, String>, String> {
+    let mut symbols = BTreeMap::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(name), Some(value)) = (fields.next(), fields.next()) else {
+            return Err(format!("line {}: expected '<name> <hex address>'", idx + 1));
+        };
+        let value = value.strip_prefix("0x").unwrap_or(value);
+        let address = u16::from_str_radix(value, 16)
+            .map_err(|_| format!("line {}: invalid address '{}'", idx + 1, value))?;
+        symbols.entry(address).or_insert_with(|| name.to_string());
+    }
+    Ok(symbols)
+}
+
+pub struct Disassembler {
+    image: Vec<u8>,
+    base: u16,
+    symbols: BTreeMap<u16, String>,
+}
+
+impl Disassembler {
+    // `image` is the memory starting at address `base`.
+    pub fn new(image: &[u8], base: u16) -> Self {
+        Disassembler {
+            image: image.to_vec(),
+            base,
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_memory(mem: &Memory, start: u16, end: u16) -> Self {
+        let image: Vec<u8> = (start..end).map(|address| mem.read_u8(address)).collect();
+        Self::new(&image, start)
+    }
+
+    pub fn add_symbol(&mut self, address: u16, name: impl Into<String>) {
+        self.symbols.entry(address).or_insert_with(|| name.into());
+    }
+
+    fn end(&self) -> u32 {
+        self.base as u32 + self.image.len() as u32
+    }
+
+    fn bytes_at(&self, address: u32) -> &[u8] {
+        &self.image[(address - self.base as u32) as usize..]
+    }
+
+    // Decodes from `start` to the end of the image, one instruction after the other.
+    // A `start` before the image begins where the image does.
+    pub fn disassemble(&self, start: u16) -> String {
+        let start = start.max(self.base);
+        let mut items = Vec::new();
+        let mut address = start as u32;
+        while address < self.end() {
+            let item = match decode(self.bytes_at(address)) {
+                Some(instruction) => Item::Code(instruction),
+                None => Item::Data,
+            };
+            let len = item.len();
+            items.push((address, item));
+            address += len as u32;
+        }
+        self.render(start, &items, &self.symbols)
+    }
+
+    // Follows the program from where the machine starts, at the reset vector, so
+    // that only bytes reachable as code are decoded and everything else is kept as
+    // data. The interrupt vector is the literal of a `MOV` placed there, as
+    // machine.md describes, and its routine is followed too.
+    pub fn traverse_vectors(&self) -> String {
+        let mut entries = vec![(RESET_VECTOR, "inicio")];
+        let reset = RESET_VECTOR as u32;
+        if (self.base as u32..self.end()).contains(&reset) {
+            if let Some(Instruction {
+                mnemonic,
+                operands,
+                len: 4,
+            }) = decode(self.bytes_at(reset))
+            {
+                if let ("MOV", [Operand::Register(_), Operand::Literal(routine)]) =
+                    (mnemonic.as_str(), &operands[..])
+                {
+                    entries.push((*routine, "interrupcao"));
+                }
+            }
+        }
+        self.trace(&entries)
+    }
+
+    // Like `traverse_vectors`, for programs that start at known addresses instead.
+    pub fn traverse(&self, entries: &[u16]) -> String {
+        let entries: Vec<(u16, &str)> = entries.iter().map(|&entry| (entry, "")).collect();
+        self.trace(&entries)
+    }
+
+    fn trace(&self, entries: &[(u16, &str)]) -> String {
+        let mut labels = self.symbols.clone();
+        let mut items: BTreeMap<u32, Item> = BTreeMap::new();
+        // Bytes already claimed by an instruction.
+        let mut used = vec![false; self.image.len()];
+
+        let mut pending: Vec<u16> = Vec::new();
+        for &(entry, name) in entries.iter().rev() {
+            if !name.is_empty() {
+                labels.entry(entry).or_insert_with(|| name.to_string());
+            }
+            pending.push(entry);
+        }
+        while let Some(mut address) = pending.pop() {
+            loop {
+                let address32 = address as u32;
+                if address32 < self.base as u32 || address32 >= self.end() {
+                    break;
+                }
+                let offset = (address32 - self.base as u32) as usize;
+                let Some(instruction) = decode(self.bytes_at(address32)) else {
+                    break;
+                };
+                let range = offset..offset + instruction.len;
+                if used[range.clone()].iter().any(|&used| used) {
+                    break;
+                }
+                used[range].fill(true);
+
+                let mnemonic = instruction.mnemonic.as_str();
+                let target = instruction
+                    .operands
+                    .iter()
+                    .find_map(|operand| match operand {
+                        Operand::Address(target) => Some(*target),
+                        _ => None,
+                    });
+                if let Some(target) = target {
+                    let prefix = if mnemonic == "JSB" { "rotina" } else { "l" };
+                    labels
+                        .entry(target)
+                        .or_insert_with(|| format!("{}_{:04X}", prefix, target));
+                    pending.push(target);
+                }
+                let next = address32 + instruction.len as u32;
+                let falls_through = !matches!(mnemonic, "JMP" | "HLT" | "RSB" | "RSI");
+                items.insert(address32, Item::Code(instruction));
+                if !falls_through || next > 0xFFFF {
+                    break;
+                }
+                address = next as u16;
+            }
+        }
+
+        let mut address = self.base as u32;
+        while address < self.end() {
+            let offset = (address - self.base as u32) as usize;
+            if !used[offset] {
+                items.insert(address, Item::Data);
+            }
+            address += items.get(&address).map_or(1, Item::len) as u32;
+        }
+        let items: Vec<(u32, Item)> = items.into_iter().collect();
+        self.render(self.base, &items, &labels)
+    }
+
+    // Symbols that do not fall on an item boundary are written as constants, so
+    // that every reference still assembles.
+    fn render(&self, start: u16, items: &[(u32, Item)], labels: &BTreeMap<u16, String>) -> String {
+        let mut out = String::new();
+        let boundaries: Vec<u32> = items.iter().map(|(address, _)| *address).collect();
+        for (address, name) in labels {
+            if boundaries.binary_search(&(*address as u32)).is_err() {
+                writeln!(out, "{}: .const 0x{:04X}", name, address).unwrap();
+            }
+        }
+        if start != 0 {
+            writeln!(out, "        .org 0x{:04X}", start).unwrap();
+        }
+
+        let mut data: Vec<(u32, u8)> = Vec::new();
+        for (idx, (address, item)) in items.iter().enumerate() {
+            if let Some(label) = labels.get(&(*address as u16)) {
+                flush(&mut out, &mut data);
+                writeln!(out, "{}:", label).unwrap();
+            }
+            let bytes = &self.bytes_at(*address)[..item.len()];
+            match item {
+                Item::Code(instruction) => {
+                    flush(&mut out, &mut data);
+                    line(&mut out, &format(instruction, labels), *address, bytes);
+                }
+                Item::Data => {
+                    data.push((*address, bytes[0]));
+                    let next = items.get(idx + 1);
+                    if data.len() == 8 || next.is_none_or(|(_, item)| !matches!(item, Item::Data)) {
+                        flush(&mut out, &mut data);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+enum Item {
+    Code(Instruction),
+    Data,
+}
+
+impl Item {
+    fn len(&self) -> usize {
+        match self {
+            Item::Code(instruction) => instruction.len,
+            Item::Data => 1,
+        }
+    }
+}
+
+fn flush(out: &mut String, data: &mut Vec<(u32, u8)>) {
+    if let Some(&(address, _)) = data.first() {
+        let bytes: Vec<u8> = data.iter().map(|(_, byte)| *byte).collect();
+        let values: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+        line(
+            out,
+            &format!(".byte {}", values.join(", ")),
+            address,
+            &bytes,
+        );
+        data.clear();
+    }
+}
+
+fn address_text(address: u16, labels: &BTreeMap<u16, String>) -> String {
+    match labels.get(&address) {
+        Some(name) => name.clone(),
+        None => format!("0x{:04X}", address),
+    }
+}
+
+fn format(instruction: &Instruction, labels: &BTreeMap<u16, String>) -> String {
+    let operands: Vec<String> = instruction
+        .operands
+        .iter()
+        .map(|operand| match operand {
+            Operand::Register(reg) => format!("R{}", reg),
+            Operand::Indirect(reg) => format!("R{}*", reg),
+            Operand::Literal(value) => format!("0x{:04X}", value),
+            Operand::Byte(value) => format!("0x{:02X}", value),
+            Operand::Address(address) => address_text(*address, labels),
+        })
+        .collect();
+    match operands.is_empty() {
+        true => instruction.mnemonic.clone(),
+        false => format!("{} {}", instruction.mnemonic, operands.join(", ")),
+    }
+}
+
+fn line(out: &mut String, text: &str, address: u32, bytes: &[u8]) {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    writeln!(
+        out,
+        "        {:<24} ; {:04X}: {}",
+        text,
+        address,
+        bytes.join(" ")
+    )
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::machine::Machine;
+
+    fn text(bytes: &[u8]) -> Option<String> {
+        let instruction = decode(bytes)?;
+        assert_eq!(instruction.len, bytes.len());
+        Some(format(&instruction, &BTreeMap::new()))
+    }
+
+    #[test]
+    fn test_decode_table() {
+        assert_eq!(text(&[0b0000_1000]).as_deref(), Some("HLT"));
+        assert_eq!(
+            text(&[0b0001_0001, 0, 0xFF, 0xFF]).as_deref(),
+            Some("MOV R0, 0xFFFF")
+        );
+        assert_eq!(text(&[0b0001_0010, 0x01]).as_deref(), Some("MOV R0*, R1"));
+        assert_eq!(text(&[0b0001_0011, 0x20]).as_deref(), Some("MOV R2, R0*"));
+        assert_eq!(
+            text(&[0b0001_0101, 1, 10]).as_deref(),
+            Some("MOVB R1, 0x0A")
+        );
+        assert_eq!(text(&[0b0001_1000, 3]).as_deref(), Some("PHR R3"));
+        assert_eq!(text(&[0b0010_1100, 0x10]).as_deref(), Some("ADDB R1, R0"));
+        assert_eq!(text(&[0b0101_0100, 4]).as_deref(), Some("INCB R4"));
+        assert_eq!(text(&[0b1001_1000, 2]).as_deref(), Some("JMP R2*"));
+        assert_eq!(
+            text(&[0b1010_1001, 0x00, 0x01]).as_deref(),
+            Some("JSB 0x0100")
+        );
+        assert_eq!(text(&[0b1010_0000, 0x13]).as_deref(), Some("JNZ R3"));
+        assert_eq!(
+            text(&[0b1010_0001, 5, 0x10, 0x00]).as_deref(),
+            Some("JNO 0x0010")
+        );
+    }
+
+    #[test]
+    fn test_decode_rejects_invalid_forms() {
+        // Unknown opcode, NOP with a mode, ADD with an indirect origin, MOVB with an
+        // indirect origin, jump mode 6, register 16 and a truncated literal.
+        for bytes in [
+            &[0b1111_1000][..],
+            &[0b0000_0001],
+            &[0b0010_1011, 0x01],
+            &[0b0001_0111, 0x01],
+            &[0b1010_0001, 6, 0, 0],
+            &[0b0001_1000, 16],
+            &[0b0001_0001, 0, 0xFF],
+        ] {
+            assert_eq!(decode(bytes), None, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_disassemble_with_symbols() {
+        let mut disassembler = Disassembler::new(
+            &[
+                0b0001_0001,
+                0,
+                0x00,
+                0xF0,
+                0xFF,
+                0xFE,
+                0b1001_1001,
+                0x00,
+                0x01,
+            ],
+            0x100,
+        );
+        disassembler.add_symbol(0x100, "inicio");
+        disassembler.add_symbol(0xF000, "TELA");
+        assert_eq!(
+            disassembler.disassemble(0x100),
+            "\
+TELA: .const 0xF000
+        .org 0x0100
+inicio:
+        MOV R0, 0xF000           ; 0100: 11 00 00 F0
+        .byte 0xFF, 0xFE         ; 0104: FF FE
+        JMP inicio               ; 0106: 99 00 01
+"
+        );
+        assert_eq!(
+            disassembler.disassemble(0x80),
+            disassembler.disassemble(0x100)
+        );
+    }
+
+    #[test]
+    fn test_disassembly_reassembles() {
+        let source = "
+            inicio: MOV r0, 3
+                    MOVB r1, 0x80
+            laco:   ADD r1, r0
+                    DEC r0
+                    JNZ laco
+                    MOV r2, 0x8000
+                    MOV r2*, r1
+                    JSB fim
+                    .byte 0xFF, 0xF8
+            fim:    JMP r3*
+                    HLT
+        ";
+        let rom = Assembler::new().assemble(source).unwrap();
+        let mut mem = Memory::new();
+        mem.load_rom(&rom);
+        let mut disassembler = Disassembler::from_memory(&mem, 0, rom.len() as u16);
+        for (address, name) in
+            parse_symbols("inicio 0000\nlaco 0x0007\n; comentário\nfim 001A").unwrap()
+        {
+            disassembler.add_symbol(address, name);
+        }
+        let text = disassembler.disassemble(0);
+        assert!(text.contains("        JNZ laco "), "{}", text);
+        assert_eq!(Assembler::new().assemble(&text).unwrap(), rom);
+    }
+
+    #[test]
+    fn test_traversal_separates_code_and_data() {
+        let source = "
+            comeco: MOV r0, trata
+                    MOV r0, 3
+            laco:   DEC r0
+                    JNZ laco
+                    JSB rotina
+                    HLT
+            tabela: .byte 0x11, 0x00, 0xFF
+                    .ascii \"Oi\"
+            rotina: MOV r1, r0
+                    RSB
+            trata:  RSI
+        ";
+        let rom = Assembler::new().assemble(source).unwrap();
+        let text = Disassembler::new(&rom, 0).traverse_vectors();
+        assert_eq!(
+            text,
+            "inicio:
+        MOV R0, 0x001A           ; 0000: 11 00 1A 00
+        MOV R0, 0x0003           ; 0004: 11 00 03 00
+l_0008:
+        DEC R0                   ; 0008: 58 00
+        JNZ l_0008               ; 000A: A1 01 08 00
+        JSB rotina_0017          ; 000E: A9 17 00
+        HLT                      ; 0011: 08
+        .byte 0x11, 0x00, 0xFF, 0x4F, 0x69 ; 0012: 11 00 FF 4F 69
+rotina_0017:
+        MOV R1, R0               ; 0017: 10 10
+        RSB                      ; 0019: B0
+interrupcao:
+        RSI                      ; 001A: C8
+"
+        );
+        assert_eq!(Assembler::new().assemble(&text).unwrap(), rom);
+
+        // The ROM is one the machine can run from the reset vector.
+        let mut machine = Machine::new();
+        let mut mem = Memory::new();
+        mem.load_rom(&rom);
+        for _ in 0..100 {
+            if machine.halted() {
+                break;
+            }
+            machine.step(&mut mem);
+        }
+        assert!(machine.halted());
+    }
+
+    #[test]
+    fn test_parse_symbols_errors() {
+        assert_eq!(
+            parse_symbols("inicio 0000\nfim"),
+            Err("line 2: expected '<name> <hex address>'".to_string())
+        );
+        assert_eq!(
+            parse_symbols("fim 12345"),
+            Err("line 1: invalid address '12345'".to_string())
+        );
+    }
+}
@@ -0,0 +1,360 @@
+use crate::memory::{ROM_BASE, ROM_SIZE};
+use std::fmt;
+use std::path::Path;
+
+// Bytes per data record in the text formats, as most programmers expect.
+const RECORD_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Raw,
+    IntelHex,
+    SRecord,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FormatError(pub String);
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid ROM image: {}", self.0)
+    }
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "raw" | "bin" => Some(Format::Raw),
+            "ihex" | "hex" => Some(Format::IntelHex),
+            "srec" => Some(Format::SRecord),
+            _ => None,
+        }
+    }
+
+    // The format usually meant by a file name: `.hex` is Intel HEX, `.srec`,
+    // `.s19` and `.mot` are S-records, anything else is raw.
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("hex" | "ihex") => Format::IntelHex,
+            Some("srec" | "s19" | "mot") => Format::SRecord,
+            _ => Format::Raw,
+        }
+    }
+
+    // Text images are recognized by their first record, which must be complete
+    // and have a good checksum; anything else is raw.
+    pub fn detect(bytes: &[u8]) -> Format {
+        let first = bytes
+            .split(|byte| *byte == b'\n')
+            .map(|line| line.trim_ascii())
+            .find(|line| !line.is_empty());
+        let valid = |text: &[u8], record: fn(usize, &str) -> Result<Vec<u8>, FormatError>| {
+            std::str::from_utf8(text).is_ok_and(|text| record(0, text).is_ok())
+        };
+        match first {
+            Some([b':', rest @ ..]) if valid(rest, intel_hex_bytes) => Format::IntelHex,
+            Some([b'S', b'0'..=b'9', rest @ ..]) if valid(rest, s_record_bytes) => Format::SRecord,
+            _ => Format::Raw,
+        }
+    }
+}
+
+// Writes a ROM image, which starts at ROM_BASE.
+pub fn encode(rom: &[u8], format: Format) -> Vec<u8> {
+    match format {
+        Format::Raw => rom.to_vec(),
+        Format::IntelHex => intel_hex(rom).into_bytes(),
+        Format::SRecord => s_record(rom).into_bytes(),
+    }
+}
+
+// Reads a ROM image in any of the formats. Bytes not given by a text image are
+// zero, as in a raw one.
+pub fn decode(bytes: &[u8]) -> Result<Vec<u8>, FormatError> {
+    let records = match Format::detect(bytes) {
+        Format::Raw => {
+            if bytes.len() > ROM_SIZE {
+                return Err(FormatError(format!(
+                    "{} bytes do not fit in the {} bytes of ROM",
+                    bytes.len(),
+                    ROM_SIZE
+                )));
+            }
+            return Ok(bytes.to_vec());
+        }
+        Format::IntelHex => read_intel_hex(bytes)?,
+        Format::SRecord => read_s_record(bytes)?,
+    };
+
+    let mut rom = Vec::new();
+    for (line, address, data) in records {
+        let start = address
+            .checked_sub(ROM_BASE as u32)
+            .map(|offset| offset as usize)
+            .filter(|offset| offset + data.len() <= ROM_SIZE)
+            .ok_or_else(|| {
+                FormatError(format!(
+                    "line {}: record at 0x{:X} is outside ROM",
+                    line, address
+                ))
+            })?;
+        if rom.len() < start + data.len() {
+            rom.resize(start + data.len(), 0);
+        }
+        rom[start..start + data.len()].copy_from_slice(&data);
+    }
+    Ok(rom)
+}
+
+fn intel_hex(rom: &[u8]) -> String {
+    let mut out = String::new();
+    for (idx, chunk) in rom.chunks(RECORD_SIZE).enumerate() {
+        let address = ROM_BASE as usize + idx * RECORD_SIZE;
+        let mut record = vec![chunk.len() as u8, (address >> 8) as u8, address as u8, 0x00];
+        record.extend_from_slice(chunk);
+        let checksum = record
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+            .wrapping_neg();
+        record.push(checksum);
+        out += &format!(":{}\n", hex(&record));
+    }
+    out += ":00000001FF\n";
+    out
+}
+
+fn s_record(rom: &[u8]) -> String {
+    let mut out = s_line('0', 0, b"cupana");
+    for (idx, chunk) in rom.chunks(RECORD_SIZE).enumerate() {
+        out += &s_line('1', ROM_BASE + (idx * RECORD_SIZE) as u16, chunk);
+    }
+    out + &s_line('9', ROM_BASE, &[])
+}
+
+// A record with a 16-bit address: S0 (header), S1 (data) or S9 (start address).
+fn s_line(kind: char, address: u16, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8 + 3, (address >> 8) as u8, address as u8];
+    record.extend_from_slice(data);
+    let checksum = !record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(checksum);
+    format!("S{}{}\n", kind, hex(&record))
+}
+
+type Record = (usize, u32, Vec<u8>);
+
+fn read_intel_hex(bytes: &[u8]) -> Result<Vec<Record>, FormatError> {
+    let mut records = Vec::new();
+    let mut base = 0;
+    for (line, text) in lines(bytes) {
+        let Some(rest) = text.strip_prefix(':') else {
+            return Err(FormatError(format!("line {}: expected ':'", line)));
+        };
+        let record = intel_hex_bytes(line, rest)?;
+        let address = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => records.push((line, base + address, data.to_vec())),
+            0x01 => break,
+            kind @ (0x02 | 0x04) if data.len() != 2 => {
+                return Err(FormatError(format!(
+                    "line {}: record type {:02X} has {} data bytes, expected 2",
+                    line,
+                    kind,
+                    data.len()
+                )))
+            }
+            0x02 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            0x04 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            // Start addresses mean nothing to the machine, which always resets to 0.
+            0x03 | 0x05 => {}
+            kind => {
+                return Err(FormatError(format!(
+                    "line {}: unsupported record type {:02X}",
+                    line, kind
+                )))
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn read_s_record(bytes: &[u8]) -> Result<Vec<Record>, FormatError> {
+    let mut records = Vec::new();
+    for (line, text) in lines(bytes) {
+        let mut chars = text.chars();
+        let (Some('S'), Some(kind)) = (chars.next(), chars.next()) else {
+            return Err(FormatError(format!("line {}: expected 'S'", line)));
+        };
+        let record = s_record_bytes(line, chars.as_str())?;
+        let width = match kind {
+            '1' => 2,
+            '2' => 3,
+            '3' => 4,
+            '0' | '5' | '6' | '7' | '8' | '9' => continue,
+            _ => {
+                return Err(FormatError(format!(
+                    "line {}: unsupported record type S{}",
+                    line, kind
+                )))
+            }
+        };
+        let fields = &record[1..record.len() - 1];
+        if fields.len() < width {
+            return Err(FormatError(format!("line {}: record is too short", line)));
+        }
+        let address = fields[..width]
+            .iter()
+            .fold(0u32, |address, byte| address << 8 | *byte as u32);
+        records.push((line, address, fields[width..].to_vec()));
+    }
+    Ok(records)
+}
+
+// The bytes of an Intel HEX record after the ':', with its length and checksum
+// checked.
+fn intel_hex_bytes(line: usize, text: &str) -> Result<Vec<u8>, FormatError> {
+    let record = parse_hex(line, text)?;
+    let Some(&count) = record.first() else {
+        return Err(FormatError(format!("line {}: empty record", line)));
+    };
+    if record.len() != count as usize + 5 {
+        return Err(FormatError(format!(
+            "line {}: record has {} bytes, expected {}",
+            line,
+            record.len(),
+            count as usize + 5
+        )));
+    }
+    if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err(FormatError(format!("line {}: bad checksum", line)));
+    }
+    Ok(record)
+}
+
+// The same for an S-record, after the 'S' and its type.
+fn s_record_bytes(line: usize, text: &str) -> Result<Vec<u8>, FormatError> {
+    let record = parse_hex(line, text)?;
+    let Some(&count) = record.first() else {
+        return Err(FormatError(format!("line {}: empty record", line)));
+    };
+    if record.len() != count as usize + 1 {
+        return Err(FormatError(format!(
+            "line {}: record has {} bytes, expected {}",
+            line,
+            record.len(),
+            count as usize + 1
+        )));
+    }
+    if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xFF {
+        return Err(FormatError(format!("line {}: bad checksum", line)));
+    }
+    Ok(record)
+}
+
+// Non-empty lines with their numbers, starting at 1.
+fn lines(bytes: &[u8]) -> impl Iterator<Item = (usize, String)> + '_ {
+    bytes
+        .split(|byte| *byte == b'\n')
+        .enumerate()
+        .map(|(idx, line)| {
+            (
+                idx + 1,
+                String::from_utf8_lossy(line.trim_ascii()).into_owned(),
+            )
+        })
+        .filter(|(_, line)| !line.is_empty())
+}
+
+fn parse_hex(line: usize, text: &str) -> Result<Vec<u8>, FormatError> {
+    if !text.len().is_multiple_of(2) || !is_hex_text(text.as_bytes()) {
+        return Err(FormatError(format!("line {}: invalid hex digits", line)));
+    }
+    Ok((0..text.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&text[idx..idx + 2], 16).unwrap())
+        .collect())
+}
+
+fn is_hex_text(bytes: &[u8]) -> bool {
+    bytes.iter().all(u8::is_ascii_hexdigit)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intel_hex() {
+        let rom: Vec<u8> = (0..20).collect();
+        let text = String::from_utf8(encode(&rom, Format::IntelHex)).unwrap();
+        assert_eq!(
+            text,
+            ":10000000000102030405060708090A0B0C0D0E0F78\n\
+             :0400100010111213A6\n\
+             :00000001FF\n"
+        );
+        assert_eq!(Format::detect(text.as_bytes()), Format::IntelHex);
+        assert_eq!(decode(text.as_bytes()).unwrap(), rom);
+
+        // Records may come in any order and leave gaps.
+        let sparse = ":020010001122BB\r\n:0100000008F7\r\n:00000001FF\r\n";
+        let mut expected = vec![0; 0x12];
+        expected[0] = 0x08;
+        expected[0x10..].copy_from_slice(&[0x11, 0x22]);
+        assert_eq!(decode(sparse.as_bytes()).unwrap(), expected);
+
+        let err = decode(b":0100000008F7\n:0100000008F6\n").unwrap_err();
+        assert_eq!(err.to_string(), "invalid ROM image: line 2: bad checksum");
+        let err = decode(b":03000002000000FB\n").unwrap_err();
+        assert_eq!(err.0, "line 1: record type 02 has 3 data bytes, expected 2");
+        let err = decode(b":0100000008F7\n:01800000017E\n").unwrap_err();
+        assert_eq!(err.0, "line 2: record at 0x8000 is outside ROM");
+    }
+
+    #[test]
+    fn test_s_record() {
+        let rom: Vec<u8> = (0..20).collect();
+        let text = String::from_utf8(encode(&rom, Format::SRecord)).unwrap();
+        assert_eq!(
+            text,
+            "S0090000637570616E617E\n\
+             S1130000000102030405060708090A0B0C0D0E0F74\n\
+             S107001010111213A2\n\
+             S9030000FC\n"
+        );
+        assert_eq!(Format::detect(text.as_bytes()), Format::SRecord);
+        assert_eq!(decode(text.as_bytes()).unwrap(), rom);
+
+        // S2 and S3 records carry wider addresses.
+        let wide = "S20500000208F0\nS3060000000108F0\nS804000000FB\n";
+        assert_eq!(decode(wide.as_bytes()).unwrap(), [0, 0x08, 0x08]);
+
+        let err = decode(b"S107001010111213A2\nS1040000080F\n").unwrap_err();
+        assert_eq!(err.0, "line 2: bad checksum");
+    }
+
+    #[test]
+    fn test_raw_and_formats() {
+        assert_eq!(encode(&[0x11, 0x00], Format::Raw), [0x11, 0x00]);
+        assert_eq!(Format::detect(&[0x11, 0x00, 0x3A]), Format::Raw);
+        // Raw bytes that only look like a text record.
+        assert_eq!(Format::detect(b":0123\n"), Format::Raw);
+        assert_eq!(Format::detect(b":0100000008F6\n"), Format::Raw);
+        assert_eq!(Format::detect(b"S1040000080F"), Format::Raw);
+        assert_eq!(decode(&[0x11, 0x00]).unwrap(), [0x11, 0x00]);
+        assert_eq!(
+            decode(&vec![0; ROM_SIZE + 1]).unwrap_err().0,
+            "32769 bytes do not fit in the 32768 bytes of ROM"
+        );
+
+        assert_eq!(Format::from_name("ihex"), Some(Format::IntelHex));
+        assert_eq!(Format::from_name("elf"), None);
+        assert_eq!(Format::from_path(Path::new("jogo.hex")), Format::IntelHex);
+        assert_eq!(Format::from_path(Path::new("jogo.s19")), Format::SRecord);
+        assert_eq!(Format::from_path(Path::new("jogo.bin")), Format::Raw);
+    }
+}
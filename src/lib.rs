@@ -0,0 +1,8 @@
+pub mod disassembler;
+pub mod formats;
+pub mod linker;
+pub mod machine;
+pub mod memory;
+
+pub use cupana_asm::{assembler, object};
+pub use cupana_macros::casm;
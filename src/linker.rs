@@ -0,0 +1,1139 @@
+mod script;
+
+use crate::assembler::Assembler;
+use crate::machine::RESET_VECTOR;
+use crate::memory::{RAM_BASE, ROM_BASE, ROM_SIZE, STACK_BASE, STACK_SIZE};
+use crate::object::{section_kind, Archive, Object, Part, Section, Target};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
+pub use script::Script;
+
+const CRT0: &str = include_str!("linker/crt0.casm");
+
+#[derive(Debug, PartialEq)]
+pub struct LinkError(pub String);
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Where one input section ended up. `load` differs from `address` only for `.data`,
+// whose initial contents are stored in ROM and copied to RAM at startup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub object: String,
+    pub section: String,
+    pub address: u16,
+    pub load: u16,
+    pub size: usize,
+}
+
+// The code the machine starts at and the interrupt vector, the literal of its
+// first instruction.
+const VECTOR_TABLE_SIZE: usize = 4;
+
+// A section dropped because nothing kept refers to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discarded {
+    pub object: String,
+    pub section: String,
+    pub size: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    pub rom: Vec<u8>,
+    pub sections: Vec<Placement>,
+    pub symbols: BTreeMap<String, i64>,
+    pub discarded: Vec<Discarded>,
+}
+
+impl Program {
+    // Bytes of ROM and of RAM the discarded sections would have taken. `.data`
+    // counts in both, as its initial contents are stored in ROM.
+    pub fn saved(&self) -> (usize, usize) {
+        self.discarded.iter().fold((0, 0), |(rom, ram), discarded| {
+            match section_kind(&discarded.section) {
+                Some(".data") => (rom + discarded.size, ram + discarded.size),
+                Some(".bss") => (rom, ram + discarded.size),
+                _ => (rom + discarded.size, ram),
+            }
+        })
+    }
+
+    // One `<name> <address>` line per symbol, as read back by the disassembler.
+    pub fn map(&self) -> String {
+        self.symbols
+            .iter()
+            .filter(|(_, value)| (0..=0xFFFF).contains(*value))
+            .map(|(name, value)| format!("{:<24} {:04X}\n", name, value))
+            .collect()
+    }
+}
+
+struct Layout {
+    sections: Vec<Placement>,
+    rom_end: usize,
+    data_load: usize,
+    data: Range<usize>,
+    bss: Range<usize>,
+    stack: Range<usize>,
+}
+
+impl Layout {
+    // Placements of the sections of each object, in the order they were added.
+    fn by_object<'a>(&'a self, objects: &[(String, &Object)]) -> Vec<&'a [Placement]> {
+        let mut start = 0;
+        objects
+            .iter()
+            .map(|(_, object)| {
+                start += object.sections.len();
+                &self.sections[start - object.sections.len()..start]
+            })
+            .collect()
+    }
+}
+
+pub struct Linker {
+    objects: Vec<(String, Object)>,
+    archives: Vec<(String, Archive)>,
+    script: Script,
+    gc: bool,
+    keep: Vec<String>,
+    startup: bool,
+}
+
+impl Default for Linker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Linker {
+            objects: Vec::new(),
+            archives: Vec::new(),
+            script: Script::default(),
+            gc: false,
+            keep: Vec::new(),
+            startup: false,
+        }
+    }
+
+    // Links in the startup code of `crt0.casm`, which prepares memory and calls
+    // `main`.
+    pub fn set_startup(&mut self, startup: bool) {
+        self.startup = startup;
+    }
+
+    // Replaces the memory map of `memory.rs`.
+    pub fn set_script(&mut self, script: Script) {
+        self.script = script;
+    }
+
+    // Drops the sections the program can never reach.
+    pub fn set_gc(&mut self, gc: bool) {
+        self.gc = gc;
+    }
+
+    // Keeps the section defining `symbol`, and what it refers to, when sections
+    // are dropped.
+    pub fn keep(&mut self, symbol: impl Into<String>) {
+        self.keep.push(symbol.into());
+    }
+
+    pub fn add_object(&mut self, name: impl Into<String>, object: Object) {
+        self.objects.push((name.into(), object));
+    }
+
+    pub fn add_archive(&mut self, name: impl Into<String>, archive: Archive) {
+        self.archives.push((name.into(), archive));
+    }
+
+    pub fn link(&self) -> Result<Program, LinkError> {
+        let crt0 = self.startup.then(|| self.crt0());
+        let mut objects = self.select(crt0.as_ref());
+        let mut discarded = Vec::new();
+        let kept;
+        if self.gc {
+            (kept, discarded) = collect(&objects, &self.script, &self.keep)?;
+            objects = kept
+                .iter()
+                .map(|(name, object)| (name.clone(), object))
+                .collect();
+        }
+        let layout = place(&objects, &self.script)?;
+        if let (Some(_), Some(placement)) = (&crt0, layout.sections.first()) {
+            if placement.address != RESET_VECTOR {
+                return Err(LinkError(format!(
+                    "startup code must be placed at 0x{:04X}, not 0x{:04X}",
+                    RESET_VECTOR, placement.address
+                )));
+            }
+        }
+        let symbols = symbols(&objects, &layout)?;
+        let mut rom = vec![0; layout.rom_end - ROM_BASE as usize];
+
+        for ((name, object), placed) in objects.iter().zip(layout.by_object(&objects)) {
+            for (section, placement) in object.sections.iter().zip(placed) {
+                if section.data.is_empty() {
+                    continue;
+                }
+                let start = (placement.load - ROM_BASE) as usize;
+                let bytes = &mut rom[start..start + section.size];
+                bytes.copy_from_slice(&section.data);
+
+                for relocation in &section.relocations {
+                    let target = match &relocation.target {
+                        Target::Section(target) => object
+                            .sections
+                            .iter()
+                            .zip(placed)
+                            .find(|(section, _)| section.name == *target)
+                            .map(|(_, placement)| placement.address as i64)
+                            .ok_or_else(|| {
+                                LinkError(format!(
+                                    "{}: relocation against unknown section '{}'",
+                                    name, target
+                                ))
+                            })?,
+                        Target::Symbol(symbol) => *symbols.get(symbol).ok_or_else(|| {
+                            LinkError(format!(
+                                "{}: relocation against undeclared symbol '{}'",
+                                name, symbol
+                            ))
+                        })?,
+                    };
+                    let value = target + relocation.addend;
+                    let value = match relocation.part {
+                        Part::Full => value,
+                        Part::Lo => value & 0xFF,
+                        Part::Hi => (value >> 8) & 0xFF,
+                    };
+                    let fits = match relocation.size {
+                        1 => (-0x80..=0xFF).contains(&value),
+                        _ => (-0x8000..=0xFFFF).contains(&value),
+                    };
+                    if !fits {
+                        return Err(LinkError(format!(
+                            "{}: relocated value {} does not fit in {} bits at {}+0x{:X}",
+                            name,
+                            value,
+                            relocation.size * 8,
+                            section.name,
+                            relocation.offset
+                        )));
+                    }
+                    let field = &mut bytes[relocation.offset..][..relocation.size as usize];
+                    field.copy_from_slice(&(value as u16).to_le_bytes()[..field.len()]);
+                }
+            }
+        }
+
+        Ok(Program {
+            rom,
+            sections: layout.sections,
+            symbols,
+            discarded,
+        })
+    }
+
+    // The startup code uses the program's own `interrupcao`, if some object or
+    // archive member exports one, or else returns from interrupts right away.
+    fn crt0(&self) -> Object {
+        let mut assembler = Assembler::new();
+        let exports = self
+            .objects
+            .iter()
+            .chain(
+                self.archives
+                    .iter()
+                    .flat_map(|(_, archive)| &archive.members),
+            )
+            .flat_map(|(_, object)| &object.exports);
+        if exports
+            .into_iter()
+            .any(|symbol| symbol.name == "interrupcao")
+        {
+            assembler.add_define("INTERRUPCAO").unwrap();
+        }
+        assembler
+            .assemble_object(CRT0)
+            .expect("crt0.casm should assemble")
+    }
+
+    // Every object, plus the archive members that define a symbol still undefined,
+    // searching the archives again until nothing new is needed.
+    fn select<'a>(&'a self, crt0: Option<&'a Object>) -> Vec<(String, &'a Object)> {
+        let mut objects: Vec<(String, &Object)> = crt0
+            .map(|crt0| ("crt0.o".to_string(), crt0))
+            .into_iter()
+            .chain(
+                self.objects
+                    .iter()
+                    .map(|(name, object)| (name.clone(), object)),
+            )
+            .collect();
+        let mut taken = HashSet::new();
+        loop {
+            let defined: HashSet<&str> = objects
+                .iter()
+                .flat_map(|(_, object)| object.exports.iter().map(|s| s.name.as_str()))
+                .collect();
+            let undefined: HashSet<&str> = objects
+                .iter()
+                .flat_map(|(_, object)| object.imports.iter().map(String::as_str))
+                .filter(|name| !defined.contains(name))
+                .collect();
+
+            let mut needed = Vec::new();
+            for (archive, contents) in &self.archives {
+                for (member, object) in &contents.members {
+                    if !taken.contains(&(archive, member))
+                        && object
+                            .exports
+                            .iter()
+                            .any(|s| undefined.contains(s.name.as_str()))
+                    {
+                        needed.push((archive, member, object));
+                    }
+                }
+            }
+            if needed.is_empty() {
+                return objects;
+            }
+            for (archive, member, object) in needed {
+                taken.insert((archive, member));
+                objects.push((format!("{}({})", archive, member), object));
+            }
+        }
+    }
+}
+
+type Collected = (Vec<(String, Object)>, Vec<Discarded>);
+
+// The objects without the sections unreachable from the code the script places
+// at the reset vector, the sections it fixes with `at` or the symbols to keep.
+fn collect(
+    objects: &[(String, &Object)],
+    script: &Script,
+    keep: &[String],
+) -> Result<Collected, LinkError> {
+    let mut owners = HashMap::new();
+    for (idx, (_, object)) in objects.iter().enumerate() {
+        for symbol in &object.exports {
+            let section = symbol
+                .section
+                .as_ref()
+                .and_then(|name| object.sections.iter().position(|s| s.name == *name));
+            owners.insert(symbol.name.as_str(), section.map(|section| (idx, section)));
+        }
+    }
+
+    // Where the sections would go if all of them were kept. Dropping others moves
+    // neither the sections at the reset vector, which start their region, nor the
+    // ones fixed with `at`.
+    let inputs: Vec<((usize, usize), &Section, usize)> = objects
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, (_, object))| {
+            object
+                .sections
+                .iter()
+                .enumerate()
+                .filter_map(move |(section, contents)| {
+                    script
+                        .rule(&contents.name)
+                        .map(|rule| ((idx, section), contents, rule))
+                })
+        })
+        .collect();
+    let sections: Vec<&Section> = inputs.iter().map(|(_, section, _)| *section).collect();
+    let rules: Vec<usize> = inputs.iter().map(|(_, _, rule)| *rule).collect();
+    let arrangement = arrange(&sections, &rules, script);
+    let vectors = RESET_VECTOR as usize..RESET_VECTOR as usize + VECTOR_TABLE_SIZE;
+    let mut pending = Vec::new();
+    for ((input, section, rule), address) in inputs.iter().zip(arrangement.addresses) {
+        let fixed = script.rules[*rule].at.is_some();
+        if fixed || (address < vectors.end && vectors.start < address + section.size) {
+            pending.push(*input);
+        }
+    }
+    for symbol in keep {
+        match owners.get(symbol.as_str()) {
+            Some(owner) => pending.extend(*owner),
+            None => {
+                return Err(LinkError(format!(
+                    "symbol '{}' to keep is not defined",
+                    symbol
+                )))
+            }
+        }
+    }
+
+    let mut live: Vec<Vec<bool>> = objects
+        .iter()
+        .map(|(_, object)| vec![false; object.sections.len()])
+        .collect();
+    while let Some((idx, section)) = pending.pop() {
+        if std::mem::replace(&mut live[idx][section], true) {
+            continue;
+        }
+        let object = objects[idx].1;
+        for relocation in &object.sections[section].relocations {
+            match &relocation.target {
+                Target::Section(name) => pending.extend(
+                    object
+                        .sections
+                        .iter()
+                        .position(|s| s.name == *name)
+                        .map(|section| (idx, section)),
+                ),
+                Target::Symbol(symbol) => {
+                    pending.extend(owners.get(symbol.as_str()).copied().flatten())
+                }
+            }
+        }
+    }
+
+    let mut kept = Vec::new();
+    let mut discarded = Vec::new();
+    for ((name, object), live) in objects.iter().zip(live) {
+        let mut object = (*object).clone();
+        let mut live = live.into_iter();
+        let (sections, dropped): (Vec<Section>, Vec<Section>) = object
+            .sections
+            .into_iter()
+            .partition(|_| live.next().unwrap());
+        object.sections = sections;
+        // Symbols only the dropped code used no longer need a definition, but
+        // ones defined nowhere are still reported.
+        let used: HashSet<&str> = object
+            .sections
+            .iter()
+            .flat_map(|section| &section.relocations)
+            .filter_map(|relocation| match &relocation.target {
+                Target::Symbol(symbol) => Some(symbol.as_str()),
+                Target::Section(_) => None,
+            })
+            .collect();
+        object.imports.retain(|symbol| {
+            used.contains(symbol.as_str()) || !owners.contains_key(symbol.as_str())
+        });
+        object.exports.retain(|symbol| {
+            symbol
+                .section
+                .as_ref()
+                .is_none_or(|section| !dropped.iter().any(|s| s.name == *section))
+        });
+        discarded.extend(
+            dropped
+                .iter()
+                .filter(|section| section.size > 0)
+                .map(|section| Discarded {
+                    object: name.clone(),
+                    section: section.name.clone(),
+                    size: section.size,
+                }),
+        );
+        kept.push((name.clone(), object));
+    }
+    Ok((kept, discarded))
+}
+
+// Each section goes to the region its rule names, in the order of the rules. The
+// initial contents of the sections copied at startup are stored in their load
+// region after everything placed there.
+fn place(objects: &[(String, &Object)], script: &Script) -> Result<Layout, LinkError> {
+    let inputs: Vec<(&String, &Section)> = objects
+        .iter()
+        .flat_map(|(name, object)| object.sections.iter().map(move |s| (name, s)))
+        .collect();
+    let mut rules = Vec::with_capacity(inputs.len());
+    for (name, section) in &inputs {
+        let Some(rule) = script.rule(&section.name) else {
+            return Err(LinkError(format!(
+                "{}: section '{}' has no place in the memory map",
+                name, section.name
+            )));
+        };
+        rules.push(rule);
+    }
+
+    let sections: Vec<&Section> = inputs.iter().map(|(_, section)| *section).collect();
+    let Arrangement {
+        addresses,
+        mut cursors,
+        data,
+        bss,
+        copy,
+        overrun,
+    } = arrange(&sections, &rules, script);
+    if let Some((rule, cursor)) = overrun {
+        let rule = &script.rules[rule];
+        return Err(LinkError(format!(
+            "section '{}' cannot start at 0x{:04X}: region '{}' is already used up to 0x{:04X}",
+            rule.pattern,
+            rule.at.unwrap(),
+            script.regions[rule.region].name,
+            cursor
+        )));
+    }
+    let data = data.unwrap_or(RAM_BASE as usize..RAM_BASE as usize);
+    let bss = bss.unwrap_or(data.end..data.end);
+    let data_load = match copy {
+        Some(load) => {
+            cursors[load] += data.len();
+            cursors[load] - data.len()
+        }
+        None => data.start,
+    };
+
+    let sections: Vec<Placement> = inputs
+        .iter()
+        .zip(addresses)
+        .zip(&rules)
+        .map(|(((name, section), address), rule)| {
+            let load = match script.rules[*rule].load {
+                Some(_) => data_load + address - data.start,
+                None => address,
+            };
+            Placement {
+                object: name.to_string(),
+                section: section.name.clone(),
+                address: address as u16,
+                load: load as u16,
+                size: section.size,
+            }
+        })
+        .collect();
+
+    for (idx, region) in script.regions.iter().enumerate() {
+        let used = cursors[idx] - region.start;
+        if used <= region.size {
+            continue;
+        }
+        let mut message = format!(
+            "region '{}' needs {} bytes but only {} are available:",
+            region.name, used, region.size
+        );
+        for (placement, rule) in sections.iter().zip(&rules) {
+            if script.rules[*rule].region == idx {
+                message += &format!(
+                    "\n  {} {}: {} bytes at 0x{:04X}",
+                    placement.object, placement.section, placement.size, placement.address
+                );
+            }
+        }
+        if copy == Some(idx) && !data.is_empty() {
+            message += &format!(
+                "\n  initial values copied to RAM: {} bytes at 0x{:04X}",
+                data.len(),
+                data_load
+            );
+        }
+        return Err(LinkError(message));
+    }
+
+    // Only ROM is loaded into the machine, so that is where contents must go.
+    let mut rom_end = ROM_BASE as usize;
+    for ((_, section), placement) in inputs.iter().zip(&sections) {
+        let load = placement.load as usize;
+        if section.data.is_empty() {
+            continue;
+        }
+        if load < ROM_BASE as usize || load + section.size > ROM_BASE as usize + ROM_SIZE {
+            return Err(LinkError(format!(
+                "{}: section '{}' has contents at 0x{:04X}, outside ROM",
+                placement.object, placement.section, load
+            )));
+        }
+        rom_end = rom_end.max(load + section.size);
+    }
+
+    let stack = match script.region_named("stack") {
+        Some(region) => region.start..region.start + region.size,
+        None => STACK_BASE as usize..STACK_BASE as usize + STACK_SIZE,
+    };
+    Ok(Layout {
+        sections,
+        rom_end,
+        data_load,
+        data,
+        bss,
+        stack,
+    })
+}
+
+// The addresses the rules of a script give to the sections, before checking that
+// they fit.
+struct Arrangement {
+    addresses: Vec<usize>,
+    // Where the use of each region ends.
+    cursors: Vec<usize>,
+    data: Option<Range<usize>>,
+    bss: Option<Range<usize>>,
+    copy: Option<usize>,
+    // The first rule whose `at` address its region had already used, and how far.
+    overrun: Option<(usize, usize)>,
+}
+
+// `rules[i]` is the rule that takes `sections[i]`. Each region is filled rule by
+// rule, and within a rule in the order the sections were given.
+fn arrange(sections: &[&Section], rules: &[usize], script: &Script) -> Arrangement {
+    let mut cursors: Vec<usize> = script.regions.iter().map(|region| region.start).collect();
+    let mut addresses = vec![0; sections.len()];
+    let mut data: Option<Range<usize>> = None;
+    let mut bss: Option<Range<usize>> = None;
+    let mut copy = None;
+    let mut overrun = None;
+    for (idx, rule) in script.rules.iter().enumerate() {
+        let cursor = &mut cursors[rule.region];
+        if let Some(at) = rule.at {
+            if at < *cursor && overrun.is_none() {
+                overrun = Some((idx, *cursor));
+            }
+            *cursor = at;
+        }
+        let start = *cursor;
+        for (input, section) in sections.iter().enumerate() {
+            if rules[input] == idx {
+                *cursor = cursor.next_multiple_of(section.align);
+                addresses[input] = *cursor;
+                *cursor += section.size;
+            }
+        }
+        if rule.load.is_some() {
+            copy = rule.load;
+            data = Some(data.map_or(start, |data| data.start)..*cursor);
+        }
+        if section_kind(&rule.pattern) == Some(".bss") {
+            bss = Some(bss.map_or(start, |bss| bss.start)..*cursor);
+        }
+    }
+    Arrangement {
+        addresses,
+        cursors,
+        data,
+        bss,
+        copy,
+        overrun,
+    }
+}
+
+fn symbols(
+    objects: &[(String, &Object)],
+    layout: &Layout,
+) -> Result<BTreeMap<String, i64>, LinkError> {
+    let mut symbols = BTreeMap::new();
+    let mut owners = HashMap::new();
+    for (name, value) in [
+        ("__data_load", layout.data_load),
+        ("__data_start", layout.data.start),
+        ("__data_end", layout.data.end),
+        ("__bss_start", layout.bss.start),
+        ("__bss_end", layout.bss.end),
+        ("__stack_start", layout.stack.start),
+        ("__stack_end", layout.stack.end),
+    ] {
+        symbols.insert(name.to_string(), value as i64);
+        owners.insert(name, "the linker");
+    }
+
+    for ((name, object), placed) in objects.iter().zip(layout.by_object(objects)) {
+        for symbol in &object.exports {
+            if let Some(owner) = owners.get(symbol.name.as_str()) {
+                return Err(LinkError(format!(
+                    "symbol '{}' is defined in both {} and {}",
+                    symbol.name, owner, name
+                )));
+            }
+            let base = match &symbol.section {
+                Some(section) => object
+                    .sections
+                    .iter()
+                    .zip(placed)
+                    .find(|(s, _)| s.name == *section)
+                    .map(|(_, placement)| placement.address as i64)
+                    .ok_or_else(|| {
+                        LinkError(format!(
+                            "{}: symbol '{}' is in unknown section '{}'",
+                            name, symbol.name, section
+                        ))
+                    })?,
+                None => 0,
+            };
+            symbols.insert(symbol.name.clone(), base + symbol.value);
+            owners.insert(symbol.name.as_str(), name.as_str());
+        }
+    }
+
+    for (name, object) in objects {
+        if let Some(missing) = object.imports.iter().find(|s| !symbols.contains_key(*s)) {
+            return Err(LinkError(format!(
+                "undefined symbol '{}' referenced in {}",
+                missing, name
+            )));
+        }
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::machine::Machine;
+    use crate::memory::Memory;
+
+    fn object(source: &str) -> Object {
+        Assembler::new().assemble_object(source).unwrap()
+    }
+
+    fn principal() -> Object {
+        object(
+            "
+            .extern dobra, entrada
+            .global saida
+                    MOV r1, entrada
+                    MOV r0, r1*
+                    JSB dobra
+                    MOV r1, saida
+                    MOV r1*, r0
+                    HLT
+            .bss
+            saida:  .short 0
+            ",
+        )
+    }
+
+    fn rotinas() -> Object {
+        object(
+            "
+            .global dobra, entrada
+            dobra:  ADD r0, r0
+                    RSB
+            .rodata
+            entrada: .short 21
+            .data
+            contador: .short 5
+            ",
+        )
+    }
+
+    #[test]
+    fn test_link_runs() {
+        let mut linker = Linker::new();
+        linker.add_object("principal.o", principal());
+        linker.add_object("rotinas.o", rotinas());
+        let program = linker.link().unwrap();
+
+        let placed: Vec<_> = program
+            .sections
+            .iter()
+            .map(|p| (p.object.as_str(), p.section.as_str(), p.address, p.load))
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                ("principal.o", ".text", 0x0000, 0x0000),
+                ("principal.o", ".bss", 0x8002, 0x8002),
+                ("rotinas.o", ".text", 0x0010, 0x0010),
+                ("rotinas.o", ".rodata", 0x0013, 0x0013),
+                ("rotinas.o", ".data", 0x8000, 0x0015),
+            ]
+        );
+        assert_eq!(program.symbols["dobra"], 0x0010);
+        assert_eq!(program.symbols["saida"], 0x8002);
+        assert_eq!(program.symbols["__data_load"], 0x0015);
+        assert_eq!(program.symbols["__bss_end"], 0x8004);
+        assert_eq!(&program.rom[0x13..], [21, 0, 5, 0]);
+
+        let mut mem = Memory::new();
+        let mut machine = Machine::new();
+        mem.load_rom(&program.rom);
+        while !machine.halted() {
+            machine.step(&mut mem);
+        }
+        assert_eq!(mem.read_u16(0x8002), 42);
+    }
+
+    #[test]
+    fn test_link_aligns_sections() {
+        let mut linker = Linker::new();
+        linker.add_object("a.o", object("NOP\n.rodata\n.byte 1"));
+        linker.add_object(
+            "b.o",
+            object(".global tabela\n.rodata\n.align 4\ntabela: .short 1"),
+        );
+        let program = linker.link().unwrap();
+
+        let addresses: Vec<_> = program.sections.iter().map(|p| p.address).collect();
+        assert_eq!(addresses, [0x0000, 0x0001, 0x0004]);
+        assert_eq!(program.symbols["tabela"], 0x0004);
+        assert_eq!(program.rom, [0, 1, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_link_pulls_needed_archive_members() {
+        let member = |name: &str, source| (name.to_string(), object(source));
+        let archive = Archive {
+            members: vec![
+                member("triplica.o", ".global triplica\ntriplica: MUL r0, 3\nRSB"),
+                member(
+                    "dobra.o",
+                    ".extern soma\n.global dobra\ndobra: MOV r1, r0\nJMP soma",
+                ),
+                member("soma.o", ".global soma\nsoma: ADD r0, r1\nRSB"),
+            ],
+        };
+        let mut linker = Linker::new();
+        linker.add_object(
+            "main.o",
+            object(".extern dobra\nMOV r0, 21\nJSB dobra\nMOV r1, 0x8000\nMOV r1*, r0\nHLT"),
+        );
+        linker.add_archive("libcupana.a", archive);
+        let program = linker.link().unwrap();
+
+        let objects: Vec<_> = program.sections.iter().map(|p| p.object.as_str()).collect();
+        assert_eq!(
+            objects,
+            ["main.o", "libcupana.a(dobra.o)", "libcupana.a(soma.o)"]
+        );
+        assert!(!program.symbols.contains_key("triplica"));
+
+        let mut mem = Memory::new();
+        let mut machine = Machine::new();
+        mem.load_rom(&program.rom);
+        while !machine.halted() {
+            machine.step(&mut mem);
+        }
+        assert_eq!(mem.read_u16(0x8000), 42);
+    }
+
+    #[test]
+    fn test_link_drops_unreachable_sections() {
+        let util = || {
+            object(
+                "
+                .global dobra, triplica, tabela
+                .extern soma
+                .section text.dobra
+                dobra:    ADD r0, r0
+                          RSB
+                .section text.triplica
+                triplica: MUL r0, 3
+                          JMP soma
+                .rodata
+                tabela:   .byte 1, 2, 3
+                .bss
+                buffer:   .short 0
+                ",
+            )
+        };
+        let main = ".extern dobra\nMOV r0, 21\nJSB dobra\nMOV r1, 0x8000\nMOV r1*, r0\nHLT";
+        let soma = || object(".global soma\nsoma: ADD r0, r1\nRSB");
+        let mut linker = Linker::new();
+        linker.add_object("main.o", object(main));
+        linker.add_object("util.o", util());
+        linker.add_object("soma.o", soma());
+        linker.set_gc(true);
+        let program = linker.link().unwrap();
+
+        let placed: Vec<_> = program
+            .sections
+            .iter()
+            .map(|p| (p.object.as_str(), p.section.as_str(), p.address))
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                ("main.o", ".text", 0x0000),
+                ("util.o", ".text.dobra", 0x000E)
+            ]
+        );
+        let discarded: Vec<_> = program
+            .discarded
+            .iter()
+            .map(|d| (d.section.as_str(), d.size))
+            .collect();
+        assert_eq!(
+            discarded,
+            [
+                (".text.triplica", 7),
+                (".rodata", 3),
+                (".bss", 2),
+                (".text", 3)
+            ]
+        );
+        assert_eq!(program.saved(), (13, 2));
+        assert!(!program.symbols.contains_key("triplica"));
+
+        let mut mem = Memory::new();
+        let mut machine = Machine::new();
+        mem.load_rom(&program.rom);
+        while !machine.halted() {
+            machine.step(&mut mem);
+        }
+        assert_eq!(mem.read_u16(0x8000), 42);
+
+        let mut linker = Linker::new();
+        linker.add_object("main.o", object(main));
+        linker.add_object("util.o", util());
+        linker.add_object("soma.o", soma());
+        linker.set_gc(true);
+        linker.keep("tabela");
+        linker.keep("triplica");
+        let program = linker.link().unwrap();
+        assert_eq!(program.symbols["tabela"], 0x001B);
+        assert_eq!(program.symbols["soma"], 0x0018);
+        linker.keep("buffer");
+        assert_eq!(
+            linker.link().unwrap_err().0,
+            "symbol 'buffer' to keep is not defined"
+        );
+    }
+
+    #[test]
+    fn test_link_script() {
+        let script = Script::parse(
+            "
+            region boot  0x0000 0x0100 ; reserved for the bootloader
+            region rom   0x0100 0x0100
+            region ram   0x8000 0x0010
+            place .text     rom
+            place .text.irq rom at 0x01F0
+            place .rodata   rom
+            place .data     ram load rom
+            place .bss      ram
+            ",
+        )
+        .unwrap();
+        let source = "
+            .global irq
+            MOV r0, 1
+            .section text.irq
+            irq: RSI
+            .data
+            valor: .short 7
+            ";
+        let mut linker = Linker::new();
+        linker.add_object("a.o", object(source));
+        linker.set_script(script);
+        let program = linker.link().unwrap();
+
+        let placed: Vec<_> = program
+            .sections
+            .iter()
+            .map(|p| (p.section.as_str(), p.address, p.load))
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                (".text", 0x0100, 0x0100),
+                (".text.irq", 0x01F0, 0x01F0),
+                (".data", 0x8000, 0x01F1),
+            ]
+        );
+        assert_eq!(program.rom.len(), 0x01F3);
+        assert_eq!(program.rom[0x01F0..], [0xC8, 7, 0]);
+
+        linker.add_object("b.o", object(".space 0xF0"));
+        assert_eq!(
+            linker.link().unwrap_err().0,
+            "section '.text.irq' cannot start at 0x01F0: region 'rom' is already used up to 0x01F4"
+        );
+
+        let mut linker = Linker::new();
+        linker.add_object("a.o", object(source));
+        linker.add_object(
+            "c.o",
+            object(".bss\n.space 16\n.section bss.fila\n.space 4"),
+        );
+        linker.set_script(
+            Script::parse(
+                "region rom 0 0x100\nregion ram 0x8000 0x10\n\
+                 place .text rom\nplace .rodata rom\nplace .data ram load rom\nplace .bss ram",
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            linker.link().unwrap_err().0,
+            "region 'ram' needs 22 bytes but only 16 are available:\n  \
+             a.o .data: 2 bytes at 0x8000\n  \
+             c.o .bss: 16 bytes at 0x8002\n  \
+             c.o .bss.fila: 4 bytes at 0x8012"
+        );
+    }
+
+    #[test]
+    fn test_link_script_drops_unreachable_sections() {
+        let script = Script::parse(
+            "
+            region boot 0x0000 0x0010
+            region rom  0x0010 0x0100
+            region ram  0x8000 0x0010
+            place .text.boot boot
+            place .text      rom
+            place .text.irq  rom at 0x00F0
+            place .rodata    rom
+            place .data      ram load rom
+            place .bss       ram
+            ",
+        )
+        .unwrap();
+        let mut linker = Linker::new();
+        linker.add_object(
+            "util.o",
+            object(".global sobra\nsobra: MUL r0, 3\nRSB\n.section text.irq\ntratador: RSI"),
+        );
+        linker.add_object(
+            "main.o",
+            object(
+                ".global principal\nprincipal: MOV r0, 21\nADD r0, r0\n\
+                 MOV r1, 0x8000\nMOV r1*, r0\nHLT",
+            ),
+        );
+        linker.add_object(
+            "boot.o",
+            object(".extern principal\n.section text.boot\nJMP principal"),
+        );
+        linker.set_script(script);
+        linker.set_gc(true);
+        let program = linker.link().unwrap();
+
+        // The code at 0x0000 and the fixed interrupt handler are kept, though
+        // nothing refers to them.
+        let placed: Vec<_> = program
+            .sections
+            .iter()
+            .map(|p| (p.object.as_str(), p.section.as_str(), p.address))
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                ("util.o", ".text.irq", 0x00F0),
+                ("main.o", ".text", 0x0010),
+                ("boot.o", ".text.boot", 0x0000),
+            ]
+        );
+        let discarded: Vec<_> = program
+            .discarded
+            .iter()
+            .map(|d| (d.object.as_str(), d.section.as_str()))
+            .collect();
+        assert_eq!(discarded, [("util.o", ".text")]);
+
+        let mut mem = Memory::new();
+        let mut machine = Machine::new();
+        mem.load_rom(&program.rom);
+        while !machine.halted() {
+            machine.step(&mut mem);
+        }
+        assert_eq!(mem.read_u16(0x8000), 42);
+    }
+
+    #[test]
+    fn test_link_startup() {
+        let source = "
+            .global main, interrupcao
+            main:   MOV r1, contador
+                    MOV r0, r1*
+                    MOV r2, tabela
+                    MOV r3, r2*
+                    ADD r0, r3
+                    MOV r1, soma
+                    MOV r1*, r0
+                    RSB
+            interrupcao:
+                    RSI
+            .data
+            contador: .short 40
+            tabela:   .byte 2, 0
+            .bss
+            soma:   .short 0
+            ";
+        let mut linker = Linker::new();
+        linker.add_object("main.o", object(source));
+        linker.set_startup(true);
+        let program = linker.link().unwrap();
+        assert_eq!(program.sections[0].object, "crt0.o");
+        assert_eq!(
+            u16::from_le_bytes([program.rom[2], program.rom[3]]) as i64,
+            program.symbols["interrupcao"]
+        );
+
+        let mut mem = Memory::new();
+        let mut machine = Machine::new();
+        mem.load_rom(&program.rom);
+        mem.write_u16(0x8004, 0xBEEF);
+        while !machine.halted() {
+            machine.step(&mut mem);
+        }
+        assert_eq!(mem.read_u16(0x8000), 40);
+        assert_eq!(mem.read_u16(0x8004), 42);
+
+        // Without a handler of its own, interrupts return at once.
+        let mut linker = Linker::new();
+        linker.add_object("main.o", object(".global main\nmain: RSB"));
+        linker.set_startup(true);
+        let program = linker.link().unwrap();
+        let handler = u16::from_le_bytes([program.rom[2], program.rom[3]]) as usize;
+        assert_eq!(program.rom[handler], 0xC8);
+
+        let mut linker = Linker::new();
+        linker.add_object("a.o", object("NOP"));
+        linker.set_startup(true);
+        assert_eq!(
+            linker.link().unwrap_err().0,
+            "undefined symbol 'main' referenced in crt0.o"
+        );
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let link = |objects: Vec<(&str, Object)>| {
+            let mut linker = Linker::new();
+            for (name, object) in objects {
+                linker.add_object(name, object);
+            }
+            linker.link().unwrap_err().to_string()
+        };
+        assert_eq!(
+            link(vec![("principal.o", principal())]),
+            "undefined symbol 'dobra' referenced in principal.o"
+        );
+        assert_eq!(
+            link(vec![("a.o", rotinas()), ("b.o", rotinas())]),
+            "symbol 'dobra' is defined in both a.o and b.o"
+        );
+        assert_eq!(
+            link(vec![("a.o", object(".global __bss_end\n__bss_end: NOP"))]),
+            "symbol '__bss_end' is defined in both the linker and a.o"
+        );
+
+        let mut grande = Object::default();
+        grande.sections.push(Section {
+            name: ".rodata".to_string(),
+            size: ROM_SIZE + 1,
+            align: 1,
+            data: vec![0; ROM_SIZE + 1],
+            relocations: Vec::new(),
+        });
+        assert_eq!(
+            link(vec![("grande.o", grande.clone())]),
+            "region 'rom' needs 32769 bytes but only 32768 are available:\n  \
+             grande.o .rodata: 32769 bytes at 0x0000"
+        );
+        grande.sections[0].name = ".vetores".to_string();
+        assert_eq!(
+            link(vec![("grande.o", grande)]),
+            "grande.o: section '.vetores' has no place in the memory map"
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use super::LinkError;
+use crate::memory::{
+    DEVICE_BASE, DEVICE_SIZE, RAM_BASE, RAM_SIZE, ROM_BASE, ROM_SIZE, STACK_BASE, STACK_SIZE,
+};
+
+const ADDRESS_SPACE: usize = 0x10000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Region {
+    pub name: String,
+    pub start: usize,
+    pub size: usize,
+}
+
+// Sections named `pattern`, or `pattern.<anything>`, go to `region` in the order
+// they were given. `load` is where the initial contents of sections copied to
+// RAM at startup are stored, and `at` a fixed address for the first of them.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Rule {
+    pub pattern: String,
+    pub region: usize,
+    pub load: Option<usize>,
+    pub at: Option<usize>,
+}
+
+// Where the linker puts each section, read from a file like this one:
+//
+//     region rom 0x0000 0x8000
+//     region ram 0x8000 0x6000
+//     place .text rom
+//     place .data ram load rom
+//
+// Rules are applied in order, each one filling its region from where the
+// previous one stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    pub(super) regions: Vec<Region>,
+    pub(super) rules: Vec<Rule>,
+}
+
+// The memory map of `memory.rs`.
+impl Default for Script {
+    fn default() -> Self {
+        let region = |name: &str, start: u16, size| Region {
+            name: name.to_string(),
+            start: start as usize,
+            size,
+        };
+        let rule = |pattern: &str, region, load| Rule {
+            pattern: pattern.to_string(),
+            region,
+            load,
+            at: None,
+        };
+        Script {
+            regions: vec![
+                region("rom", ROM_BASE, ROM_SIZE),
+                region("ram", RAM_BASE, RAM_SIZE),
+                region("stack", STACK_BASE, STACK_SIZE),
+                region("devices", DEVICE_BASE, DEVICE_SIZE),
+            ],
+            rules: vec![
+                rule(".text", 0, None),
+                rule(".rodata", 0, None),
+                rule(".data", 1, Some(0)),
+                rule(".bss", 1, None),
+            ],
+        }
+    }
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Script, LinkError> {
+        let mut script = Script {
+            regions: Vec::new(),
+            rules: Vec::new(),
+        };
+        for (idx, line) in text.lines().enumerate() {
+            let error = |message: String| LinkError(format!("line {}: {}", idx + 1, message));
+            let line = line.split(';').next().unwrap_or_default();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match &words[..] {
+                [] => {}
+                ["region", name, start, size] => {
+                    let start = number(start).map_err(error)?;
+                    let size = number(size).map_err(error)?;
+                    script.add_region(name, start, size).map_err(error)?;
+                }
+                ["region", ..] => {
+                    return Err(error("expected 'region <name> <start> <size>'".to_string()))
+                }
+                ["place", pattern, region, options @ ..] => {
+                    let region = script.region(region).map_err(error)?;
+                    let (load, at) = match options {
+                        [] => (None, None),
+                        ["load", load] => (Some(script.region(load).map_err(error)?), None),
+                        ["at", at] => (None, Some(number(at).map_err(error)?)),
+                        ["load", load, "at", at] => (
+                            Some(script.region(load).map_err(error)?),
+                            Some(number(at).map_err(error)?),
+                        ),
+                        _ => return Err(error(
+                            "expected 'place <section> <region> [load <region>] [at <address>]'"
+                                .to_string(),
+                        )),
+                    };
+                    script
+                        .add_rule(Rule {
+                            pattern: pattern.to_string(),
+                            region,
+                            load,
+                            at,
+                        })
+                        .map_err(error)?;
+                }
+                ["place", ..] => {
+                    return Err(error(
+                        "expected 'place <section> <region> [load <region>] [at <address>]'"
+                            .to_string(),
+                    ))
+                }
+                [command, ..] => return Err(error(format!("unknown command '{}'", command))),
+            }
+        }
+        Ok(script)
+    }
+
+    // The rule for a section: the one with the longest pattern it matches, so
+    // `.text.irq` can go somewhere other than the rest of `.text`.
+    pub(super) fn rule(&self, section: &str) -> Option<usize> {
+        self.rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| {
+                section == rule.pattern
+                    || section
+                        .strip_prefix(&rule.pattern)
+                        .is_some_and(|rest| rest.starts_with('.'))
+            })
+            .max_by_key(|(_, rule)| rule.pattern.len())
+            .map(|(idx, _)| idx)
+    }
+
+    pub(super) fn region_named(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|region| region.name == name)
+    }
+
+    fn region(&self, name: &str) -> Result<usize, String> {
+        self.regions
+            .iter()
+            .position(|region| region.name == name)
+            .ok_or_else(|| format!("unknown region '{}'", name))
+    }
+
+    fn add_region(&mut self, name: &str, start: usize, size: usize) -> Result<(), String> {
+        if self.region_named(name).is_some() {
+            return Err(format!("region '{}' is defined twice", name));
+        }
+        let end = match start.checked_add(size) {
+            Some(end) if end <= ADDRESS_SPACE => end,
+            Some(end) => {
+                return Err(format!(
+                    "region '{}' ends past the address space, at 0x{:X}",
+                    name, end
+                ))
+            }
+            None => return Err(format!("region '{}' ends past the address space", name)),
+        };
+        if let Some(other) = self
+            .regions
+            .iter()
+            .find(|other| start < other.start + other.size && other.start < end)
+        {
+            return Err(format!(
+                "region '{}' overlaps region '{}'",
+                name, other.name
+            ));
+        }
+        self.regions.push(Region {
+            name: name.to_string(),
+            start,
+            size,
+        });
+        Ok(())
+    }
+
+    fn add_rule(&mut self, rule: Rule) -> Result<(), String> {
+        if self.rules.iter().any(|other| other.pattern == rule.pattern) {
+            return Err(format!("section '{}' is placed twice", rule.pattern));
+        }
+        let region = &self.regions[rule.region];
+        if let Some(at) = rule.at {
+            if !(region.start..region.start + region.size).contains(&at) {
+                return Err(format!(
+                    "address 0x{:04X} is outside region '{}'",
+                    at, region.name
+                ));
+            }
+        }
+        // The startup code copies one block, so everything it copies must be
+        // placed together.
+        if rule.load.is_some() {
+            if let Some((idx, copied)) = self
+                .rules
+                .iter()
+                .enumerate()
+                .rfind(|(_, other)| other.load.is_some())
+            {
+                if (copied.region, copied.load) != (rule.region, rule.load)
+                    || idx + 1 != self.rules.len()
+                {
+                    return Err(format!(
+                        "section '{}' must follow '{}', with the same regions, as both are copied at startup",
+                        rule.pattern, copied.pattern
+                    ));
+                }
+            }
+        }
+        self.rules.push(rule);
+        Ok(())
+    }
+}
+
+fn number(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number '{}'", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let default = "
+            ; memory.rs
+            region rom     0x0000 0x8000
+            region ram     0x8000 0x6000
+            region stack   0xE000 0x1000
+            region devices 0xF000 0x1000
+
+            place .text   rom
+            place .rodata rom
+            place .data   ram load rom
+            place .bss    ram
+        ";
+        assert_eq!(Script::parse(default).unwrap(), Script::default());
+
+        let script = Script::parse(
+            "region boot 0x0000 0x0800\nregion rom 0x0800 0x7800\n\
+             place .text rom\nplace .text.irq rom at 0x1000",
+        )
+        .unwrap();
+        assert_eq!(script.rule(".text"), Some(0));
+        assert_eq!(script.rule(".text.video"), Some(0));
+        assert_eq!(script.rule(".text.irq.teclado"), Some(1));
+        assert_eq!(script.rule(".textos"), None);
+        assert_eq!(script.rules[1].at, Some(0x1000));
+
+        let err = |text| Script::parse(text).unwrap_err().0;
+        assert_eq!(
+            err("region a 0 0x100\nregion b 0x80 0x100"),
+            "line 2: region 'b' overlaps region 'a'"
+        );
+        assert_eq!(
+            err("region a 0xFF00 0x200"),
+            "line 1: region 'a' ends past the address space, at 0x10100"
+        );
+        assert_eq!(
+            err("region a 0x10 0xFFFFFFFFFFFFFFFF"),
+            "line 1: region 'a' ends past the address space"
+        );
+        assert_eq!(err("place .text rom"), "line 1: unknown region 'rom'");
+        assert_eq!(
+            err("region rom 0 0x100\nplace .text rom at 0x200"),
+            "line 2: address 0x0200 is outside region 'rom'"
+        );
+        assert_eq!(
+            err("region ram 0 0x100\nregion rom 0x100 0x100\n\
+                 place .data ram load rom\nplace .bss ram\nplace .data.x ram load rom"),
+            "line 5: section '.data.x' must follow '.data', with the same regions, as both are copied at startup"
+        );
+        assert_eq!(err("section .text"), "line 1: unknown command 'section'");
+    }
+}
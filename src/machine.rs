@@ -1,99 +1,11 @@
 use crate::memory::{Memory, ROM_BASE, STACK_BASE};
+use cupana_asm::isa::{JumpMode, Opcode};
 use std::ops::{BitAnd, BitOr, BitXor, Shl, Shr};
 
 const PC: usize = 14;
 const SP: usize = 15;
-const RESET_VECTOR: u16 = 0x0000;
-const INTERRUPT_ROUTINE_VECTOR: u16 = 0x0000;
-
-#[derive(Debug, PartialEq)]
-enum Opcode {
-    NOP,
-    HLT,
-    MOV,
-    PHR,
-    PLR,
-    ADD,
-    SUB,
-    MUL,
-    DIV,
-    MOD,
-    INC,
-    DEC,
-    AND,
-    OR,
-    XOR,
-    SHL,
-    SHR,
-    NOT,
-    CMP,
-    JMP,
-    JPC,
-    JSB,
-    RSB,
-    CLI,
-    SEI,
-    RSI,
-    NONE,
-}
-
-impl From<u8> for Opcode {
-    fn from(value: u8) -> Self {
-        match value {
-            0x00 => Opcode::NOP,
-            0x01 => Opcode::HLT,
-            0x02 => Opcode::MOV,
-            0x03 => Opcode::PHR,
-            0x04 => Opcode::PLR,
-            0x05 => Opcode::ADD,
-            0x06 => Opcode::SUB,
-            0x07 => Opcode::MUL,
-            0x08 => Opcode::DIV,
-            0x09 => Opcode::MOD,
-            0x0A => Opcode::INC,
-            0x0B => Opcode::DEC,
-            0x0C => Opcode::AND,
-            0x0D => Opcode::OR,
-            0x0E => Opcode::XOR,
-            0x0F => Opcode::NOT,
-            0x10 => Opcode::SHL,
-            0x11 => Opcode::SHR,
-            0x12 => Opcode::CMP,
-            0x13 => Opcode::JMP,
-            0x14 => Opcode::JPC,
-            0x15 => Opcode::JSB,
-            0x16 => Opcode::RSB,
-            0x17 => Opcode::CLI,
-            0x18 => Opcode::SEI,
-            0x19 => Opcode::RSI,
-            _ => Opcode::NONE,
-        }
-    }
-}
-
-enum JumpMode {
-    Zero,
-    NotZero,
-    Negative,
-    NotNegative,
-    Overflow,
-    NotOverflow,
-    None,
-}
-
-impl From<u8> for JumpMode {
-    fn from(value: u8) -> Self {
-        match value {
-            0 => JumpMode::Zero,
-            1 => JumpMode::NotZero,
-            2 => JumpMode::Negative,
-            3 => JumpMode::NotNegative,
-            4 => JumpMode::Overflow,
-            5 => JumpMode::NotOverflow,
-            _ => JumpMode::None,
-        }
-    }
-}
+pub(crate) const RESET_VECTOR: u16 = 0x0000;
+const INTERRUPT_ROUTINE_VECTOR: u16 = 0x0002;
 
 pub enum Flag {
     Zero = 0x0001,
@@ -104,7 +16,7 @@ pub enum Flag {
     Halt = 0x0080,
 }
 
-fn extract_registers_from_byte(byte: u8) -> (u8, u8) {
+pub(crate) fn extract_registers_from_byte(byte: u8) -> (u8, u8) {
     let reg_a = (byte >> 4) & 0b1111;
     let reg_b = byte & 0b1111;
     (reg_a, reg_b)
@@ -115,6 +27,12 @@ pub struct Machine {
     flags: u16,
 }
 
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Machine {
     pub fn new() -> Self {
         let mut registers = [0; 16];
@@ -1068,8 +986,9 @@ impl Machine {
         if self.get_flag(Flag::InterruptPending) && self.get_flag(Flag::InterruptEnabled) {
             self.set_flag(Flag::InterruptEnabled, false);
             self.set_flag(Flag::InterruptPending, false);
-            self.push_u16(mem, self.flags);
-            self.push_u16(mem, self.registers[PC]);
+            self.push_u16(mem, self.flags).expect("Erro no push");
+            self.push_u16(mem, self.registers[PC])
+                .expect("Erro no push");
             self.registers[PC] = mem.read_u16(INTERRUPT_ROUTINE_VECTOR)
         }
 
@@ -1080,7 +999,29 @@ impl Machine {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::{assemble, Options, Sources};
+    use crate::casm;
     use crate::memory::{Memory, RAM_BASE};
+
+    // Assembles `source` into the ROM and steps until HLT.
+    fn run(source: &str) -> (Machine, Memory) {
+        run_with(source, &Options::default())
+    }
+
+    fn run_with(source: &str, options: &Options) -> (Machine, Memory) {
+        let image = assemble(&Sources::from(source), options).unwrap();
+        let mut machine = Machine::new();
+        let mut mem = Memory::new();
+        mem.load_rom(&image.rom);
+        for _ in 0..10_000 {
+            if machine.halted() {
+                break;
+            }
+            machine.step(&mut mem);
+        }
+        assert!(machine.halted(), "program did not halt");
+        (machine, mem)
+    }
     #[test]
     fn test_reset() {
         let mut machine = Machine::new();
@@ -1513,4 +1454,193 @@ mod tests {
         assert_eq!(machine.get_flag(Flag::Negative), true);
         assert_eq!(machine.get_flag(Flag::Overflow), true);
     }
+
+    #[test]
+    fn test_interrupt() {
+        let mut machine = Machine::new();
+        let mut mem = Memory::new();
+
+        // The literal of the MOV at the reset vector is the interrupt vector.
+        mem.load_rom(&[0b0001_0001, 0, 0x00, 0x01]); // MOV R0, 0x0100
+        machine.set_flag(Flag::InterruptEnabled, true);
+        machine.set_flag(Flag::InterruptPending, true);
+        machine.step(&mut mem);
+        assert_eq!(machine.registers[PC], 0x0100);
+        assert_eq!(machine.registers[SP], STACK_BASE + 4);
+        assert_eq!(mem.read_u16(STACK_BASE + 2), ROM_BASE + 4);
+        assert!(!machine.get_flag(Flag::InterruptEnabled));
+        assert!(!machine.get_flag(Flag::InterruptPending));
+    }
+
+    #[test]
+    fn test_program() {
+        let (machine, mem) = run("
+                MOV R0, 0
+                MOV R1, 1
+        soma:   ADD R0, R1
+                INC R1
+                CMP R1, 11
+                JNZ soma
+                JSB dobra
+                MOV R2, 0x8000
+                MOV R2*, R0
+                HLT
+        dobra:  SHL R0, 1
+                RSB
+        ");
+        assert_eq!(machine.registers[0], 110);
+        assert_eq!(mem.read_u16(RAM_BASE), 110);
+    }
+
+    #[test]
+    fn test_documented_example() {
+        let (_, mem) = run("
+            .org 0x0
+                MOV r1, numero_a
+                MOV r0, r1*
+                MOV r1, numero_b
+                MOV r2, r1*
+                ADD r0, r2
+                MOV r1, resultado
+                MOV r1*, r0
+                HLT
+
+            .org 0x200
+            numero_a:   .short 15
+            numero_b:   .short 27
+            resultado:  .const 0x8000
+        ");
+        assert_eq!(mem.read_u16(0x8000), 42);
+    }
+
+    #[test]
+    fn test_conditional_jump_loop() {
+        let (_, mem) = run("
+                    MOV r0, 3
+                    MOV r1, 0
+            loop:   ADD r1, 2
+                    DEC r0
+                    JNZ loop
+                    MOV r2, 0x8000
+                    MOV r2*, r1
+                    HLT
+        ");
+        assert_eq!(mem.read_u16(0x8000), 6);
+    }
+
+    #[test]
+    fn test_structured_control_flow() {
+        let options = Options {
+            defines: vec!["DOBRO".to_string()],
+            ..Options::default()
+        };
+        let (_, mem) = run_with(
+            "
+                    MOV R0, 0
+                    MOV R1, 0
+            .while R0 != 10         ; 0 + 1 + ... + 9
+                    ADD R1, R0
+                    INC R0
+            .endwhile
+                    MOV R2, 3
+                    MOV R3, 0
+            .repeat
+                    DEC R2
+            .ifdef DOBRO
+                    ADD R3, 2
+            .else
+                    INC R3
+            .endif
+            .until R2 == 0
+            .ifz R2
+                    MOV R0, 0x8000
+            .ifn R3
+                    HLT
+            .else
+                    MOV R0*, R1
+            .endif
+            .endif
+                    MOV R2, 0x8002
+                    MOV R2*, R3
+                    HLT
+            ",
+            &options,
+        );
+        assert_eq!(mem.read_u16(0x8000), 45);
+        assert_eq!(mem.read_u16(0x8002), 6);
+
+        // `<` and `>=` compare unsigned values.
+        let (_, mem) = run("
+                    MOV R0, 0
+                    MOV R1, 0
+            .while R0 < 40000
+                    ADD R0, 10000
+                    INC R1
+            .endwhile
+            .while R0 >= 15000
+                    SUB R0, 15000
+                    INC R1
+            .endwhile
+                    MOV R2, 0x8000
+                    MOV R2*, R0
+                    MOV R2, 0x8002
+                    MOV R2*, R1
+                    HLT
+        ");
+        assert_eq!(mem.read_u16(0x8000), 10000);
+        assert_eq!(mem.read_u16(0x8002), 6);
+    }
+
+    #[test]
+    fn test_optimized_loop() {
+        let options = Options {
+            optimize: true,
+            ..Options::default()
+        };
+        let (_, mem) = run_with(
+            "
+                    MOV R0, 0
+                    MOV R1, 10
+            laco:   ADD R0, R1
+                    SUB R1, 1
+                    JNZ laco
+                    MOV R2, 0x8000
+                    MOV R2*, R0
+                    HLT
+            ",
+            &options,
+        );
+        assert_eq!(mem.read_u16(0x8000), 55);
+    }
+
+    #[test]
+    fn test_casm() {
+        const ROM: [u8; 9] = casm! { MOV R0, 0xFFFF; PHR R0; PLR R1; HLT };
+        assert_eq!(
+            ROM,
+            [
+                0b0001_0001,
+                0,
+                0xFF,
+                0xFF,
+                0b0001_1000,
+                0,
+                0b0010_0000,
+                1,
+                0b0000_1000
+            ]
+        );
+
+        let mut machine = Machine::new();
+        let mut mem = Memory::new();
+        mem.load_rom(&casm! {
+            MOV R2, tabela;
+            MOV R3, R2*;
+        fim: JMP fim;
+        tabela: .byte 'c', -1
+        });
+        machine.step(&mut mem);
+        machine.step(&mut mem);
+        assert_eq!(machine.registers[3], 0xFF63);
+    }
 }
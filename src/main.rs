@@ -1,23 +1,291 @@
-pub mod machine;
-pub mod memory;
-use std::io;
+use cupana::formats::{self, Format};
+use cupana::{assembler, casm, disassembler, linker, machine, memory, object};
+use std::path::{Path, PathBuf};
+use std::{env, fs, io, process};
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("asm") => assemble(&args[1..]),
+        Some("link") => link(&args[1..]),
+        Some("ar") => archive(&args[1..]),
+        Some("dis") => disassemble(&args[1..]),
+        Some("run") => {
+            let Some(path) = args.get(1) else {
+                usage();
+            };
+            let path = Path::new(path);
+            let rom = read_or_exit(path, formats::decode(&read_input(path)));
+            run(&rom);
+        }
+        Some(_) => usage(),
+        None => {
+            let rom = casm! { MOV R0, 0x8000; PHR R0; PLR R1; HLT };
+            run(&rom);
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Uso:");
+    eprintln!(
+        "  cupana asm [-c] [-O] <arquivo.casm> [-o <saida>] [-l <listagem.lst>] [-f <formato>] [-I <diretorio>]... [-D <NOME[=valor]>]..."
+    );
+    eprintln!(
+        "  cupana link <objeto.o | biblioteca.a>... [-o <rom.bin>] [-f <formato>] [-m <mapa.map>] [-T <script.ld>] [-s] [-g] [-k <símbolo>]..."
+    );
+    eprintln!("  cupana ar <biblioteca.a> <objeto.o>...");
+    eprintln!("  cupana dis <rom.bin> [-r | -e <entrada>... | -s <inicio>] [-b <base>] [-m <mapa.map>] [-o <saida.casm>]");
+    eprintln!("  cupana run <rom.bin | rom.hex | rom.srec>");
+    eprintln!("Formatos de ROM (-f): raw, ihex, srec; o padrão vem da extensão da saída.");
+    process::exit(2);
+}
+
+fn assemble(args: &[String]) {
+    let mut input = None;
+    let mut output = None;
+    let mut listing = None;
+    let mut object = false;
+    let mut format = None;
+    let mut assembler = assembler::Assembler::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = Some(PathBuf::from(iter.next().unwrap_or_else(|| usage()))),
+            "-c" => object = true,
+            "-O" => assembler.set_optimize(true),
+            "-f" => format = Some(format_name(iter.next().unwrap_or_else(|| usage()))),
+            "-l" => listing = Some(PathBuf::from(iter.next().unwrap_or_else(|| usage()))),
+            "-I" => assembler.add_include_dir(iter.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with("-I") => assembler.add_include_dir(&arg[2..]),
+            "-D" => define(&mut assembler, iter.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with("-D") => define(&mut assembler, &arg[2..]),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => usage(),
+        }
+    }
+    let Some(input) = input else {
+        usage();
+    };
+    if object && format.is_some() {
+        usage();
+    }
+    let output = output.unwrap_or_else(|| input.with_extension(if object { "o" } else { "bin" }));
+
+    let result = if object {
+        assembler
+            .assemble_object_file(&input)
+            .map(|object| object.to_bytes())
+    } else {
+        assembler
+            .assemble_file(&input)
+            .map(|rom| formats::encode(&rom, format.unwrap_or_else(|| Format::from_path(&output))))
+    };
+    for warning in assembler.warnings() {
+        eprintln!("{}\n", warning.report());
+    }
+    let image = result.unwrap_or_else(|errors| {
+        for err in &errors {
+            eprintln!("{}\n", err.report());
+        }
+        eprintln!("{} erro(s)", errors.len());
+        process::exit(1);
+    });
+    write_output(&output, &image);
+    if let Some(listing) = listing {
+        write_output(&listing, assembler.listing().as_bytes());
+    }
+}
+
+fn link(args: &[String]) {
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut map = None;
+    let mut format = None;
+    let mut gc = false;
+    let mut linker = linker::Linker::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = Some(PathBuf::from(iter.next().unwrap_or_else(|| usage()))),
+            "-f" => format = Some(format_name(iter.next().unwrap_or_else(|| usage()))),
+            "-m" => map = Some(PathBuf::from(iter.next().unwrap_or_else(|| usage()))),
+            "-T" => {
+                let path = PathBuf::from(iter.next().unwrap_or_else(|| usage()));
+                let text = String::from_utf8_lossy(&read_input(&path)).into_owned();
+                linker.set_script(read_or_exit(&path, linker::Script::parse(&text)));
+            }
+            "-s" => linker.set_startup(true),
+            "-g" => gc = true,
+            "-k" => linker.keep(iter.next().unwrap_or_else(|| usage())),
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+    let Some(first) = inputs.first() else {
+        usage();
+    };
+    let output = output.unwrap_or_else(|| first.with_extension("bin"));
+
+    linker.set_gc(gc);
+    for input in &inputs {
+        let bytes = read_input(input);
+        let name = input.display().to_string();
+        if object::Archive::is_archive(&bytes) {
+            linker.add_archive(
+                name,
+                read_or_exit(input, object::Archive::from_bytes(&bytes)),
+            );
+        } else {
+            linker.add_object(
+                name,
+                read_or_exit(input, object::Object::from_bytes(&bytes)),
+            );
+        }
+    }
+    let program = linker.link().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    if gc {
+        for discarded in &program.discarded {
+            eprintln!(
+                "descartada: {} {} ({} bytes)",
+                discarded.object, discarded.section, discarded.size
+            );
+        }
+        let (rom, ram) = program.saved();
+        eprintln!("economizados: {} bytes de ROM e {} bytes de RAM", rom, ram);
+    }
+    let format = format.unwrap_or_else(|| Format::from_path(&output));
+    write_output(&output, &formats::encode(&program.rom, format));
+    if let Some(map) = map {
+        write_output(&map, program.map().as_bytes());
+    }
+}
+
+fn disassemble(args: &[String]) {
+    let mut input = None;
+    let mut output = None;
+    let mut map = None;
+    let mut base = 0;
+    let mut start = None;
+    let mut vectors = false;
+    let mut entries = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = Some(PathBuf::from(iter.next().unwrap_or_else(|| usage()))),
+            "-m" => map = Some(PathBuf::from(iter.next().unwrap_or_else(|| usage()))),
+            "-b" => base = address(iter.next().unwrap_or_else(|| usage())),
+            "-s" => start = Some(address(iter.next().unwrap_or_else(|| usage()))),
+            "-r" => vectors = true,
+            "-e" => entries.push(address(iter.next().unwrap_or_else(|| usage()))),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => usage(),
+        }
+    }
+    let Some(input) = input else {
+        usage();
+    };
+
+    let mut image = read_input(&input);
+    if Format::detect(&image) != Format::Raw {
+        image = read_or_exit(&input, formats::decode(&image));
+    }
+    let mut disassembler = disassembler::Disassembler::new(&image, base);
+    if let Some(map) = map {
+        let text = String::from_utf8_lossy(&read_input(&map)).into_owned();
+        let symbols = disassembler::parse_symbols(&text).unwrap_or_else(|err| {
+            eprintln!("{}: {}", map.display(), err);
+            process::exit(1);
+        });
+        for (address, name) in symbols {
+            disassembler.add_symbol(address, name);
+        }
+    }
+    let text = if vectors {
+        disassembler.traverse_vectors()
+    } else if !entries.is_empty() {
+        disassembler.traverse(&entries)
+    } else {
+        disassembler.disassemble(start.unwrap_or(base))
+    };
+    match output {
+        Some(output) => write_output(&output, text.as_bytes()),
+        None => print!("{}", text),
+    }
+}
+
+fn address(text: &str) -> u16 {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.unwrap_or_else(|_| {
+        eprintln!("Endereço inválido: {}", text);
+        process::exit(2);
+    })
+}
+
+fn archive(args: &[String]) {
+    let Some((output, inputs)) = args.split_first().filter(|(_, inputs)| !inputs.is_empty()) else {
+        usage();
+    };
+    let mut archive = object::Archive::default();
+    for input in inputs {
+        let input = Path::new(input);
+        let object = read_or_exit(input, object::Object::from_bytes(&read_input(input)));
+        let name = input
+            .file_name()
+            .map_or(input.display().to_string(), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        archive.members.push((name, object));
+    }
+    write_output(Path::new(output), &archive.to_bytes());
+}
+
+fn read_input(path: &Path) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| {
+        eprintln!("Erro ao ler {}: {}", path.display(), err);
+        process::exit(1);
+    })
+}
+
+fn read_or_exit<T, E: std::fmt::Display>(path: &Path, result: Result<T, E>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}: {}", path.display(), err);
+        process::exit(1);
+    })
+}
+
+fn format_name(name: &str) -> Format {
+    Format::from_name(name).unwrap_or_else(|| {
+        eprintln!("Formato desconhecido: {} (use raw, ihex ou srec)", name);
+        process::exit(2);
+    })
+}
+
+fn define(assembler: &mut assembler::Assembler, definition: &str) {
+    if let Err(err) = assembler.add_define(definition) {
+        eprintln!("Erro em -D {}: {}", definition, err);
+        process::exit(2);
+    }
+}
+
+fn write_output(path: &Path, bytes: &[u8]) {
+    if let Err(err) = fs::write(path, bytes) {
+        eprintln!("Erro ao escrever {}: {}", path.display(), err);
+        process::exit(1);
+    }
+}
+
+fn run(rom: &[u8]) {
     let mut mem = memory::Memory::new();
     let mut machine = machine::Machine::new();
-    let rom = [
-        0b0001_0001,
-        0,
-        0x00,
-        0x80, // mov r0, -1
-        0b0001_1000,
-        0, // phr r0
-        0b0010_0000,
-        1,           // plr r1
-        0b0000_1000, // hlt
-    ];
-
-    mem.load_rom(&rom);
+
+    mem.load_rom(rom);
 
     loop {
         let mut input = String::new();
@@ -26,7 +294,7 @@ fn main() {
             .read_line(&mut input)
             .expect("Erro ao ler entrada");
 
-        if input == "\n".to_string() {
+        if input == "\n" {
             if !machine.halted() {
                 machine.step(&mut mem);
             } else {
@@ -1,20 +1,9 @@
 use std::{fmt, ops::Range};
 
-pub const ROM_SIZE: usize = 0x8000;
-pub const RAM_SIZE: usize = 0x6000;
-pub const STACK_SIZE: usize = 0x1000;
-pub const DEVICE_SIZE: usize = 0x1000;
-pub const MEMORY_SIZE: usize = ROM_SIZE + RAM_SIZE + STACK_SIZE + DEVICE_SIZE;
-
-pub const ROM_BASE: u16 = 0x0000;
-pub const RAM_BASE: u16 = ROM_BASE + ROM_SIZE as u16;
-pub const STACK_BASE: u16 = RAM_BASE + RAM_SIZE as u16;
-pub const DEVICE_BASE: u16 = STACK_BASE + STACK_SIZE as u16;
-
-pub const ROM_END: u16 = ROM_BASE + ROM_SIZE as u16 - 1;
-pub const RAM_END: u16 = RAM_BASE + RAM_SIZE as u16 - 1;
-pub const STACK_END: u16 = STACK_BASE + STACK_SIZE as u16 - 1;
-pub const DEVICE_END: u16 = 0xFFFF;
+pub use cupana_asm::memory_map::{
+    DEVICE_BASE, DEVICE_END, DEVICE_SIZE, MEMORY_SIZE, RAM_BASE, RAM_END, RAM_SIZE, ROM_BASE,
+    ROM_END, ROM_SIZE, STACK_BASE, STACK_END, STACK_SIZE,
+};
 
 pub struct Memory {
     rom: [u8; ROM_SIZE],
@@ -23,6 +12,12 @@ pub struct Memory {
     device: [u8; DEVICE_SIZE],
 }
 
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Memory {
     pub fn new() -> Self {
         Memory {